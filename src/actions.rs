@@ -0,0 +1,118 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+use ratatui::layout::Direction;
+
+use crate::{data_models::*, finder, panes, keymap::get_keybindings_file_path, theme::get_theme_file_path};
+
+// Everything tpad can do by name. The keymap runs the action bound to a key
+// (`key` is only the default, see keybindings.toml), the command line runs an action typed by name without arguments and
+// the command palette lists them all. Commands that need an argument put
+// their name on the command line to be completed.
+pub struct Action {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub key: Option<(KeyCode, KeyModifiers)>,
+    pub run: fn(&mut App),
+}
+
+const ALT_SHIFT: KeyModifiers = KeyModifiers::ALT.union(KeyModifiers::SHIFT);
+
+const fn key(code: KeyCode, modifiers: KeyModifiers) -> Option<(KeyCode, KeyModifiers)> {
+    Some((code, modifiers))
+}
+
+pub const ACTIONS: &[Action] = &[
+    Action { name: "palette", description: "Search and run any action", key: key(KeyCode::Char('x'), KeyModifiers::ALT), run: App::open_palette },
+    Action { name: "files", description: "Fuzzy find a file under the default directory", key: key(KeyCode::Char('p'), KeyModifiers::CONTROL), run: App::open_file_finder },
+    Action { name: "o", description: "Open files (bare names go to the default directory)", key: None, run: |app| app.prompt_command("o ") },
+    Action { name: "w", description: "Save the file", key: key(KeyCode::Char('s'), KeyModifiers::CONTROL), run: App::write_active },
+    Action { name: "wq", description: "Save the file and close its tab", key: None, run: App::write_and_close },
+    Action { name: "q", description: "Close the tab", key: None, run: |app| app.run_operation(Operations::Close) },
+    Action { name: "quit", description: "Quit tpad, asking to save first", key: key(KeyCode::Char('q'), KeyModifiers::CONTROL), run: App::quit },
+    Action { name: "cl", description: "Exit tpad", key: None, run: |app| app.run_operation(Operations::Exit) },
+    Action { name: "copy", description: "Copy the selection", key: key(KeyCode::Char('c'), KeyModifiers::CONTROL), run: App::copy_selection },
+    Action { name: "paste", description: "Paste over the selection or at the cursor", key: key(KeyCode::Char('v'), KeyModifiers::CONTROL), run: App::paste },
+    Action { name: "undo-step", description: "Undo the last change", key: key(KeyCode::Char('z'), KeyModifiers::CONTROL), run: App::undo_step },
+    Action { name: "redo-step", description: "Redo the last undone change", key: key(KeyCode::Char('y'), KeyModifiers::CONTROL), run: App::redo_step },
+    Action { name: "search", description: "Search the document for a regex", key: None, run: |app| app.prompt_command("/") },
+    Action { name: "next-match", description: "Go to the next search match", key: key(KeyCode::Char('n'), KeyModifiers::ALT), run: App::next_match },
+    Action { name: "prev-match", description: "Go to the previous search match", key: key(KeyCode::Char('m'), KeyModifiers::ALT), run: App::prev_match },
+    Action { name: "replace", description: "Replace regex matches in the document", key: None, run: |app| app.prompt_command("%s/") },
+    Action { name: "count", description: "Count regex matches in the document", key: None, run: |app| app.prompt_command("count ") },
+    Action { name: "grep", description: "Search every file under a directory", key: None, run: |app| app.prompt_command("grep ") },
+    Action { name: "next-tab", description: "Switch to the tab on the right", key: key(KeyCode::Right, KeyModifiers::ALT), run: App::next_tab },
+    Action { name: "prev-tab", description: "Switch to the tab on the left", key: key(KeyCode::Left, KeyModifiers::ALT), run: App::prev_tab },
+    Action { name: "tab-1", description: "Switch to tab 1", key: key(KeyCode::Char('1'), KeyModifiers::ALT), run: |app| app.goto_tab(1) },
+    Action { name: "tab-2", description: "Switch to tab 2", key: key(KeyCode::Char('2'), KeyModifiers::ALT), run: |app| app.goto_tab(2) },
+    Action { name: "tab-3", description: "Switch to tab 3", key: key(KeyCode::Char('3'), KeyModifiers::ALT), run: |app| app.goto_tab(3) },
+    Action { name: "tab-4", description: "Switch to tab 4", key: key(KeyCode::Char('4'), KeyModifiers::ALT), run: |app| app.goto_tab(4) },
+    Action { name: "tab-5", description: "Switch to tab 5", key: key(KeyCode::Char('5'), KeyModifiers::ALT), run: |app| app.goto_tab(5) },
+    Action { name: "tab-6", description: "Switch to tab 6", key: key(KeyCode::Char('6'), KeyModifiers::ALT), run: |app| app.goto_tab(6) },
+    Action { name: "tab-7", description: "Switch to tab 7", key: key(KeyCode::Char('7'), KeyModifiers::ALT), run: |app| app.goto_tab(7) },
+    Action { name: "tab-8", description: "Switch to tab 8", key: key(KeyCode::Char('8'), KeyModifiers::ALT), run: |app| app.goto_tab(8) },
+    Action { name: "tab-9", description: "Switch to tab 9", key: key(KeyCode::Char('9'), KeyModifiers::ALT), run: |app| app.goto_tab(9) },
+    Action { name: "setdir", description: "Set the default directory", key: None, run: |app| app.prompt_command("setdir ") },
+    Action { name: "wrap", description: "Turn soft line wrapping on or off", key: key(KeyCode::Char('z'), KeyModifiers::ALT), run: |app| app.config.wrap = !app.config.wrap },
+    Action { name: "eol", description: "Convert line endings (lf or crlf)", key: None, run: |app| app.prompt_command("eol ") },
+    Action { name: "backup", description: "Keep backups on save (off, on or a directory)", key: None, run: |app| app.prompt_command("backup ") },
+    Action { name: "undotree", description: "Browse the undo history", key: None, run: |app| app.run_operation(Operations::UndoTree) },
+    Action { name: "undo", description: "Go to a history state by number", key: None, run: |app| app.prompt_command("undo ") },
+    Action { name: "earlier", description: "Step back one history state", key: None, run: |app| app.run_operation(Operations::UndoTime(-1)) },
+    Action { name: "later", description: "Step forward one history state", key: None, run: |app| app.run_operation(Operations::UndoTime(1)) },
+    Action { name: "clundo", description: "Clear the undo history", key: None, run: App::clear_undo },
+    Action { name: "select-node", description: "Select the syntax node around the selection", key: key(KeyCode::Up, KeyModifiers::ALT), run: App::select_node },
+    Action { name: "parent-node", description: "Go to the start of the enclosing syntax node", key: key(KeyCode::Up, KeyModifiers::CONTROL), run: App::goto_parent_node },
+    Action { name: "next-node", description: "Go to the next syntax node", key: key(KeyCode::Down, KeyModifiers::CONTROL), run: App::goto_next_node },
+    Action { name: "outline", description: "List the functions, types or headings of the file", key: key(KeyCode::Char('o'), KeyModifiers::CONTROL), run: App::open_outline },
+    Action { name: "split", description: "Split the pane, the new one below", key: key(KeyCode::Char('s'), KeyModifiers::ALT), run: |app| app.split_pane(Direction::Vertical) },
+    Action { name: "vsplit", description: "Split the pane, the new one to the right", key: key(KeyCode::Char('v'), KeyModifiers::ALT), run: |app| app.split_pane(Direction::Horizontal) },
+    Action { name: "close-pane", description: "Close the pane, its tab stays open", key: key(KeyCode::Char('q'), KeyModifiers::ALT), run: App::close_pane },
+    Action { name: "next-pane", description: "Focus the next pane", key: key(KeyCode::Char('w'), KeyModifiers::ALT), run: App::next_pane },
+    Action { name: "pane-left", description: "Focus the pane on the left", key: key(KeyCode::Left, ALT_SHIFT), run: |app| app.focus_toward(CursorDirection::Left) },
+    Action { name: "pane-right", description: "Focus the pane on the right", key: key(KeyCode::Right, ALT_SHIFT), run: |app| app.focus_toward(CursorDirection::Right) },
+    Action { name: "pane-up", description: "Focus the pane above", key: key(KeyCode::Up, ALT_SHIFT), run: |app| app.focus_toward(CursorDirection::Up) },
+    Action { name: "pane-down", description: "Focus the pane below", key: key(KeyCode::Down, ALT_SHIFT), run: |app| app.focus_toward(CursorDirection::Down) },
+    Action { name: "grow-pane", description: "Make the pane larger", key: key(KeyCode::Char('='), KeyModifiers::ALT), run: |app| app.resize_pane(panes::RESIZE_STEP) },
+    Action { name: "shrink-pane", description: "Make the pane smaller", key: key(KeyCode::Char('-'), KeyModifiers::ALT), run: |app| app.resize_pane(-panes::RESIZE_STEP) },
+    Action { name: "theme", description: "Open the theme file", key: None, run: |app| app.run_operation(Operations::Open(get_theme_file_path().to_string_lossy().to_string())) },
+    Action { name: "keys", description: "Open the keybindings file", key: None, run: |app| app.run_operation(Operations::Open(get_keybindings_file_path().to_string_lossy().to_string())) },
+    Action { name: "set", description: "Choose a theme", key: None, run: |app| app.show_popup(String::new(), PopupTypes::ThemeSelectPopup) },
+    Action { name: "list", description: "List commands and the default directory", key: None, run: |app| app.run_operation(Operations::List) },
+];
+
+pub fn by_name(name: &str) -> Option<&'static Action> {
+    ACTIONS.iter().find(|a| a.name == name)
+}
+
+// How a key chord is shown to the user, e.g. "Ctrl+S" or "Alt+Left".
+pub fn key_label(code: KeyCode, modifiers: KeyModifiers) -> String {
+    let mut label = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        label.push_str("Ctrl+");
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        label.push_str("Alt+");
+    }
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        label.push_str("Shift+");
+    }
+    match code {
+        KeyCode::Char(' ') => label.push_str("Space"),
+        KeyCode::Char(c) => label.push(c.to_ascii_uppercase()),
+        KeyCode::F(n) => label.push_str(&format!("F{}", n)),
+        KeyCode::Esc => label.push_str("Esc"),
+        other => label.push_str(&format!("{:?}", other)),
+    }
+    label
+}
+
+impl CommandPalette {
+    // Actions are matched on their name and description together.
+    pub fn update(&mut self) {
+        let items: Vec<String> = ACTIONS.iter().map(|a| format!("{} {}", a.name, a.description)).collect();
+        self.matches = finder::fuzzy_rank(&items, &self.query);
+        self.selected = 0;
+    }
+    pub fn selected_action(&self) -> Option<&'static Action> {
+        self.matches.get(self.selected).map(|i| &ACTIONS[*i])
+    }
+}
@@ -6,17 +6,27 @@ use ratatui::{DefaultTerminal, Frame};
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use color_eyre::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use std::io::{self};
 
+use crate::buffer::{col_at_cell, col_to_byte, grapheme_count, row_of, row_width, view_width, wrap_rows, TextBuffer};
 use crate::data_models::*;
 
+use crate::actions;
+use crate::complete;
+use crate::config::{self, Config};
+use crate::grep;
+use crate::keymap::{self, KeyLookup};
+use crate::parse_tree;
 use crate::session;
+use crate::search;
+use crate::swap;
 
 use crate::theme::Theme;
-use crate::ui::{popup, render};
+use crate::ui::{doc_view, popup, render};
 
 impl App {
     pub fn new(file_args: Vec<String>) -> App {
@@ -27,12 +37,11 @@ impl App {
         };
 
         let mut error_msg = String::new();
-        let mut render_error = false;
-        let mut undo_history: Vec<UndoStack> = Vec::new();
+        let mut undo_history: Vec<SavedUndo> = Vec::new();
 
         let mut old_docs: Vec<Document> = match session::load_session() {
             Some(session) => {
-                undo_history = session.undo_bufs;
+                undo_history = session.undo_history;
                 session
                     .saved_files
                     .iter()
@@ -41,7 +50,6 @@ impl App {
                         Err(err) => {
                             error_msg
                                 .push_str(&format!("Error loading '{}': {}\n", file_path, err));
-                            render_error = true;
                             None
                         }
                     })
@@ -50,8 +58,20 @@ impl App {
             None => Vec::new(),
         };
 
-        for (doc, stack) in old_docs.iter_mut().zip(undo_history) {
-            doc.state.undo_stack = stack;
+        // Reattach undo history by path, but only if the text is still the one it was recorded on
+        for doc in old_docs.iter_mut() {
+            let saved = match undo_history.iter().position(|u| u.file_path == doc.file_path) {
+                Some(i) => undo_history.swap_remove(i),
+                None => continue,
+            };
+            if saved.content_hash == doc.buffer_hash() {
+                doc.state.undo_stack = saved.stack;
+            } else if !saved.stack.stack.is_empty() {
+                error_msg.push_str(&format!(
+                    "Undo history for '{}' was discarded, the file changed since the last session\n",
+                    doc.file_path
+                ));
+            }
         }
 
         let new_docs: Vec<Document> = files
@@ -60,7 +80,6 @@ impl App {
                 Ok(doc) => Some(doc),
                 Err(err) => {
                     error_msg.push_str(&format!("Error loading '{}': {}\n", file_path, err));
-                    render_error = true;
                     None
                 }
             })
@@ -82,12 +101,23 @@ impl App {
             .or_else(|| std::env::current_dir().ok())
             .unwrap_or_else(|| PathBuf::from("."));
 
-        App {
+        let (config, config_error) = Config::load();
+        if let Some(e) = config_error {
+            error_msg.push_str(&format!("config.toml: {}\n", e));
+        }
+        let (keymap, key_errors) = Keymap::load();
+        for e in key_errors {
+            error_msg.push_str(&format!("keybindings.toml: {}\n", e));
+        }
+
+        let mut app = App {
             theme:Theme::load(),
             selected_theme : 0,
+            selected_undo: 0,
             popup: None,
             clipboard: ClipboardContext::new().unwrap(),
             window_height: 0,
+            window_width: 0,
             documents,
             active: 0,
             running: true,
@@ -96,7 +126,43 @@ impl App {
             curs_x: 0,
             // NEW: default directory for new files
             default_dir,
+            backup: BackupPolicy::Off,
+            pending_swaps: swap::load_swaps(),
+            last_swap_write: Instant::now(),
+            config,
+            last_autosave: Instant::now(),
+            substitution: None,
+            search_origin: None,
+            history: session::load_history(),
+            history_cursor: None,
+            history_search: None,
+            completion: None,
+            selected_completion: 0,
+            outline: Vec::new(),
+            selected_outline: 0,
+            finder: None,
+            palette: None,
+            keymap,
+            pending_keys: Vec::new(),
+            panes: vec![Pane::default()],
+            pane_layout: PaneLayout::Pane(0),
+            focused_pane: 0,
+            pane_areas: Vec::new(),
+        };
+        if !app.config.default_dir.is_empty() {
+            app.default_dir = app.resolve_dir_for_setdir(&app.config.default_dir);
+        }
+        match app.backup_policy(&app.config.backup) {
+            Ok(policy) => app.backup = policy,
+            Err(e) => error_msg.push_str(&format!("config.toml: backup: {}\n", e)),
         }
+        if error_msg.is_empty() {
+            app.show_next_swap();
+        } else {
+            // Swap recovery prompts follow once this is dismissed
+            app.show_popup(error_msg.trim_end().to_string(), PopupTypes::InfoPopup);
+        }
+        app
     }
 
     // Resolve a user-supplied path against the default_dir for bare filenames.
@@ -123,6 +189,18 @@ impl App {
     }
 
     // Resolve setdir target: absolute -> as-is, "~" or "~/" -> HOME, otherwise HOME/<input>
+    // What a `backup` value means, creating the directory it names.
+    fn backup_policy(&self, value: &str) -> std::io::Result<BackupPolicy> {
+        match value {
+            "off" => Ok(BackupPolicy::Off),
+            "on" => Ok(BackupPolicy::SameDir),
+            dir => {
+                let path = self.resolve_dir_for_setdir(dir);
+                fs::create_dir_all(&path).map(|_| BackupPolicy::Dir(path))
+            }
+        }
+    }
+
     fn resolve_dir_for_setdir(&self, input: &str) -> PathBuf {
         let p = PathBuf::from(input);
         if p.is_absolute() {
@@ -150,10 +228,36 @@ impl App {
     }
 
     pub fn run(&mut self, mut terminal: DefaultTerminal) -> Result<()> {
+        // Focus events tell us when to look for changes made by other programs
+        let _ = crossterm::execute!(io::stdout(), crossterm::event::EnableFocusChange);
+        let mut checked_path: Option<String> = None;
         while self.running {
+            // Re-check a document whenever it becomes the active tab
+            let active_path = self.documents.get(self.active).map(|d| d.file_path.clone());
+            if active_path != checked_path {
+                self.check_disk_changes();
+                checked_path = active_path;
+            }
+            self.sync_panes();
+            // Tokenizer states are only brought up to date as far as is drawn
+            if let Some(doc) = self.documents.get_mut(self.active) {
+                let upto = doc.state.scroll_offset + self.window_height as usize;
+                doc.refresh_syntax(upto);
+                doc.refresh_tree();
+            }
+            // Other panes can show other documents or further down the same one
+            for (index, pane) in self.panes.iter().enumerate() {
+                let height = self.pane_areas.get(index).map_or(0, |area| area.height as usize);
+                if let Some(doc) = self.documents.get_mut(pane.doc) {
+                    doc.refresh_syntax(pane.view.scroll_offset + height);
+                }
+            }
             let ctx = RenderContext {
                 theme: &self.theme,
                 selected_theme: &self.selected_theme,
+                selected_undo: &self.selected_undo,
+                selected_completion: &self.selected_completion,
+                selected_outline: &self.selected_outline,
                 documents: &self.documents,
                 input_buffer: &self.input_buffer,
                 popup: &self.popup,
@@ -162,23 +266,57 @@ impl App {
                 focus: &self.focus,
                 curs_x: &self.curs_x,
                 default_dir: &self.default_dir.clone(),
+                history: &self.history,
+                history_search: &self.history_search,
+                finder: &self.finder,
+                palette: &self.palette,
+                keymap: &self.keymap,
+                pending_keys: &self.pending_keys,
+                config: &self.config,
+                panes: &self.panes,
+                pane_layout: &self.pane_layout,
+                focused_pane: &self.focused_pane,
             };
 
             terminal
                 .draw(|f: &mut Frame<'_>| {
                     let layout = render::render_ui(f, &ctx);
-                    self.window_height = layout.editor_area.height;
+                    // The cursor moves and scrolls within the focused pane
+                    let area = layout.pane_areas.get(self.focused_pane).copied().unwrap_or(layout.editor_area);
+                    self.window_height = area.height;
+                    self.window_width = area.width;
+                    self.pane_areas = layout.pane_areas;
                 })
                 .unwrap();
             for doc in &mut self.documents {
                 doc.state.window_height = self.window_height as usize;
+                doc.state.tab_width = doc.editorconfig.tab_width(&self.config);
+                doc.state.window_width = doc_view::text_width(self.window_width, doc, &self.config) as usize;
+                doc.state.wrap_width = if self.config.wrap { doc.state.window_width } else { 0 };
             }
 
             self.handle_events()?;
+            if let Some(doc) = self.documents.get_mut(self.active) {
+                doc.keep_scrolloff(self.config.scrolloff);
+                doc.keep_cursor_in_view();
+            }
+            if self.last_swap_write.elapsed() >= swap::SWAP_INTERVAL {
+                swap::write_swaps(self);
+                self.last_swap_write = Instant::now();
+            }
+            if self.config.autosave > 0 && self.last_autosave.elapsed() >= Duration::from_secs(self.config.autosave) {
+                self.autosave();
+                self.last_autosave = Instant::now();
+            }
         }
+        let _ = crossterm::execute!(io::stdout(), crossterm::event::DisableFocusChange);
         Ok(())
     }
     pub fn handle_events(&mut self) -> io::Result<()> {
+        // Wake up regularly even without input so swap files keep being written
+        if !event::poll(Duration::from_millis(500))? {
+            return Ok(());
+        }
         match event::read()? {
             Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
                 if self.popup.is_some() {
@@ -188,11 +326,33 @@ impl App {
                     self.handle_key_event(key_event);
                 }
             }
+            Event::FocusGained => self.check_disk_changes(),
             _ => {}
         }
         Ok(())
     }
     pub fn handle_key_event(&mut self, key_event: KeyEvent) {
+        if self.history_search.is_some() {
+            self.handle_history_search(key_event);
+            return;
+        }
+        self.pending_keys.push(keymap::chord(key_event.code, key_event.modifiers));
+        match self.keymap.lookup(&self.pending_keys) {
+            KeyLookup::Action(action) => {
+                self.pending_keys.clear();
+                (action.run)(self);
+                return;
+            }
+            KeyLookup::Pending => return,
+            KeyLookup::Unbound => {
+                // A key that breaks off a sequence is dropped with it
+                let in_sequence = self.pending_keys.len() > 1;
+                self.pending_keys.clear();
+                if in_sequence {
+                    return;
+                }
+            }
+        }
         match (key_event.code, key_event.modifiers) {
             // Handle ':' to switch to Command mode
             (KeyCode::Char(':'), KeyModifiers::NONE) => {
@@ -201,6 +361,13 @@ impl App {
 
             // Handle 'Esc' to switch to Editor mode
             (KeyCode::Esc, KeyModifiers::NONE) => {
+                if self.search_origin.is_some() {
+                    // Cancelled search, drop the half typed pattern with it
+                    self.cancel_incremental_search();
+                    self.input_buffer.clear();
+                    self.curs_x = 0;
+                }
+                self.history_cursor = None;
                 self.focus = Windows::Editor;
             }
 
@@ -209,13 +376,20 @@ impl App {
                 match self.focus {
                     Windows::Command => {
                         let cmd = self.input_buffer.clone();
-                        self.command_run(&cmd);
+                        self.history.push(&cmd);
+                        self.history_cursor = None;
+                        // Cleared first, an action may prompt for its argument
                         self.curs_x =0;
                         self.input_buffer.clear();
+                        self.command_run(&cmd);
+                        self.search_origin = None;
                     }
                     Windows::Editor => {
                         if self.documents.is_empty() {
                             
+                        } else if self.documents[self.active].is_read_only() {
+                            // Enter on a grep result jumps to it
+                            self.open_grep_result();
                         }else{
 
                         
@@ -226,10 +400,8 @@ impl App {
                         ;
                         //if a split is initialized and the cursor is on the last line of the doc
                         //a new line is created so that he split does not create panic
-                        if offset + active_doc.state.curs_y >= active_doc.content.len(){
-                            active_doc.content.push(String::new());
-                        }
-                        if active_doc.state.curs_y >= active_doc.state.window_height-2 {
+                        active_doc.content.ensure_line(offset + active_doc.state.curs_y);
+                        if active_doc.state.curs_y >= active_doc.state.window_height.saturating_sub(2) {
                             active_doc.state.scroll_offset+=1;
                         }
                         active_doc.state.undo_stack.push(
@@ -239,7 +411,7 @@ impl App {
                         active_doc.split_lines(
                             offset+active_doc.state.curs_y,
                             split_index);
-                        if active_doc.state.curs_y < active_doc.state.window_height-2{    
+                        if active_doc.state.curs_y < active_doc.state.window_height.saturating_sub(2){    
                             move_curs(active_doc, CursorDirection::Down);
                         }
                         active_doc.state.curs_x=0;
@@ -248,53 +420,22 @@ impl App {
                 }
             }
 
-            // Handle Ctrl + Q to quit the application
-            (KeyCode::Char('q'), KeyModifiers::CONTROL) => {
-                if self.documents.is_empty() {
-                    // Show help instead of exiting when no docs are open
-                    self.show_empty_state_help();
-                } else if self.documents[self.active].state.is_dirty {
-                    self.show_popup(String::from("Save before quitting?"), PopupTypes::SaveOnClosePopup);
-                } else {
-                    self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
-                }
-            }
-
-            // Handle Alt + Left Arrow to switch tabs left
-            (KeyCode::Left, KeyModifiers::ALT) => {
-                if !self.documents.is_empty() && self.active > 0 {
-                    self.active -= 1;
-                }
-            }
-            (KeyCode::Char(c), KeyModifiers::ALT) if c.is_ascii_digit() => {
-                if self.documents.is_empty() { return; }
-                let index = c.to_digit(10).unwrap() as usize;
-                if index > 0 && index <= self.documents.len() {
-                    self.change(index - 1);
-                }
-            }
-
-            // Handle Alt + Right Arrow to switch tabs right
-            (KeyCode::Right, KeyModifiers::ALT) => {
-                if !self.documents.is_empty() && self.active < self.documents.len() - 1 {
-                    self.active += 1;
-                }
-            }
-
             (KeyCode::Right | KeyCode::Left | KeyCode::Up | KeyCode::Down, KeyModifiers::SHIFT | KeyModifiers::NONE) => {
                 match  self.focus {
                     Windows::Command => {
                         match key_event.code {
                             KeyCode::Right => {
-                                if self.curs_x+1 <= self.input_buffer.len() {
+                                if self.curs_x < grapheme_count(&self.input_buffer) {
                                     self.curs_x+=1
                                 }
                             },
                             KeyCode::Left => {
-                                if self.curs_x-1 > 0 {
+                                if self.curs_x > 0 {
                                     self.curs_x-=1
                                 }
                             },
+                            KeyCode::Up => self.history_step(true),
+                            KeyCode::Down => self.history_step(false),
 
                             _ => {}
                         }
@@ -355,11 +496,18 @@ impl App {
                         if self.input_buffer.is_empty() {
                             return;
                         }
-                        self.input_buffer.remove(self.curs_x.saturating_sub(1));
-                        self.curs_x = self.curs_x.saturating_sub(1);
+                        if self.curs_x == 0 {
+                            return;
+                        }
+                        let start = col_to_byte(&self.input_buffer, self.curs_x - 1);
+                        let end = col_to_byte(&self.input_buffer, self.curs_x);
+                        self.input_buffer.replace_range(start..end, "");
+                        self.curs_x -= 1;
+                        self.history_cursor = None;
+                        self.update_incremental_search();
                     }
                     Windows::Editor => {
-                        if self.documents.is_empty() { return; }
+                        if self.documents.is_empty() || self.refuse_read_only() { return; }
                         let active_doc = &mut self.documents[self.active];
                         let offset = active_doc.state.scroll_offset;
                         // Check if the cursor is at the beginning of the line.
@@ -390,7 +538,7 @@ impl App {
                                     }
                                     active_doc.state.undo_stack.push(EditOp::MergeLines { 
                                         merged_line: (offset + active_doc.state.curs_y).saturating_sub(1), 
-                                        merge_point: active_doc.content[(offset + active_doc.state.curs_y).saturating_sub(1)].len(), 
+                                        merge_point: active_doc.content.line_len((offset + active_doc.state.curs_y).saturating_sub(1)), 
                                         applied: false });
                                        
                                     active_doc.merge_lines(
@@ -401,7 +549,7 @@ impl App {
                                     // Delete the character on the left of the cursor.
                                     
                                     
-                                   let ch = active_doc.content[offset+active_doc.state.curs_y].chars().nth(active_doc.state.curs_x - 1).unwrap();
+                                   let ch = active_doc.content.grapheme_at(offset+active_doc.state.curs_y, active_doc.state.curs_x - 1).unwrap();
                                     active_doc.state.undo_stack.push(EditOp::DeleteChar {
                                         line: offset + active_doc.state.curs_y,
                                         col: active_doc.state.curs_x-1,
@@ -421,236 +569,49 @@ impl App {
                     }
                 }
             }
-            (KeyCode::Char('c') | KeyCode::Char('v'), KeyModifiers::CONTROL) =>{
-                if self.documents.is_empty() { return; }
-                let active_doc = &mut self.documents[self.active];
-                match key_event.code {
-                    KeyCode::Char('c') =>{
-                        match active_doc.state.selection {
-                            Some(val) =>{
-                                let(line1, col1, line2, col2) = {
-                                    let (y1, x1) = val.0;
-                                    let (y2, x2) = val.1;
-
-                                    if y1 < y2 {
-                                        (y1, x1, y2, x2)
-                                    }
-                                    else{
-                                        (y2, x2, y1, x1)
-                                    }
-
-                                };
-                                
-                                let mut copy_content: Vec<String> = Vec::new();
-                                let mut copy_buffer = String::new();
-                                if line1 == line2 {
-                                    copy_buffer = active_doc.content[line1].as_str()[col2.min(col1)..col1.max(col2)].to_string();
-                                }else{
-                                    copy_content.push(active_doc.content[line1].as_str()[col1..].to_string());
-                                    if line2 - line1 != 0 {
-                                        for line in &active_doc.content[line1+1..line2]{
-                                            copy_content.push(line.clone());
-                                        }
-                                    }
-                                    copy_content.push(active_doc.content[line2].as_str()[..col2].to_string());
-                                    copy_buffer = copy_content.join("\n");
-                                        
-                                }
-                                self.clipboard = ClipboardContext::new().unwrap();
-                                self.clipboard.set_contents(copy_buffer.to_owned()).unwrap();
-                                fs::write("log.txt", format!("{},{} {},{} \n {}", line1,col1,line2,col2, self.clipboard.get_contents().unwrap())).ok();
-                            },
-                            None => {},
-                        }
-                    }
-                    KeyCode::Char('v') =>{
-                        let active_doc = &mut self.documents[self.active];
-                        let offset = active_doc.state.scroll_offset;
-
-                        // If there is a selection, delete it first
-                        let (insert_line, insert_col) = if let Some(sel) = active_doc.state.selection.take() {
-                            // normalize selection bounds
-                            let ((y1, x1), (y2, x2)) = (sel.0, sel.1);
-                            let (start, stop) = if (y1, x1) <= (y2, x2) {
-                                ((y1, x1), (y2, x2))
-                            } else {
-                                ((y2, x2), (y1, x1))
-                            };
-                            // delete the selected range
-                            let deleted = active_doc.delete_selection(start, stop);
-                            active_doc.state.undo_stack.push(
-                                EditOp::DeleteSelection {
-                                    start,
-                                    stop,
-                                    selection: deleted,
-                                    applied: false,
-                                }
-                            );
-                            // move cursor to start of deleted region
-                            active_doc.adjust_cursor(start.0, start.1, false);
-                            // start paste at that position
-                            start
-                        } else {
-                            // no selection: paste at current cursor
-                            (offset + active_doc.state.curs_y, active_doc.state.curs_x)
-                        };
-
-                        // Now perform the paste
-                        if let Some(line) = active_doc.content.get_mut(insert_line) {
-                            let clipboard_text = self.clipboard.get_contents().unwrap_or_default();
-                            let lines: Vec<&str> = clipboard_text.split('\n').collect();
-                            // compute stop position
-                            let stop = if lines.len() > 1 {
-                                (insert_line + lines.len() - 1, lines.last().unwrap().len())
-                            } else {
-                                (insert_line, insert_col + lines[0].len())
-                            };
-                            // insert the clipboard text
-                            active_doc.insert_selection((insert_line, insert_col), clipboard_text.clone());
-                            // record for undo
-                            active_doc.state.undo_stack.push(
-                                EditOp::InsetSelection {
-                                    applied: false,
-                                    start: (insert_line, insert_col),
-                                    stop,
-                                    selection: clipboard_text,
-                                }
-                            );
-                            // move cursor to end of inserted text
-                            active_doc.adjust_cursor(stop.0, stop.1, false);
-                        }
-                        
-                    }
-                    _=>{}
+            (KeyCode::Tab, KeyModifiers::NONE) => {
+                match self.focus {
+                    Windows::Command => self.complete_input(),
+                    Windows::Editor => self.insert_indent(),
                 }
             }
 
-            (KeyCode::Char('s'), KeyModifiers::CONTROL) => {
-                if self.documents.is_empty() {
-                    self.show_popup("No file to save. Use ': o <file>' to open one.".to_string(), PopupTypes::InfoPopup);
-                } else {
-                    match self.documents[self.active].save_file() {
-                        Ok(_ ) => {
-                            self.theme = Theme::load();
-                        }
-                        Err(e) =>{
-                            self.show_popup(e.to_string(), PopupTypes::ErrorPopup)
-                        }
-                    }
-                }
-            },
-
-            (KeyCode::Char('z') | KeyCode::Char('y'), KeyModifiers::CONTROL) => {
-                if self.documents.is_empty() { return; }
-                let active_doc = &mut self.documents[self.active];
-                match key_event.code {
-                    KeyCode::Char('z') => {
-                        active_doc.undo();
-                    }
-                    KeyCode::Char('y') => {
-                        active_doc.redo();
-                    }
-                    _ =>{}
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                if let Windows::Command = self.focus {
+                    let kind = HistoryKind::of(&self.input_buffer);
+                    self.history_search = Some(HistorySearch {
+                        kind,
+                        query: String::new(),
+                        found: self.history.find_back(kind, "", None),
+                    });
                 }
             }
 
-            (KeyCode::Char('n') | KeyCode::Char('m'), KeyModifiers::ALT) => {
-                if self.documents.is_empty() { return; }
-                let active_doc = &mut self.documents[self.active];
-                if active_doc.state.find_active {
-                    match key_event.code {
-                        KeyCode::Char('n') => {
-                            if !active_doc.state.highlights.is_empty() {
-                                let len = active_doc.state.highlights.len();
-                                active_doc.state.current_match = (active_doc.state.current_match + 1) % len;
-                                let h = active_doc.state.highlights[active_doc.state.current_match];
-                                active_doc.adjust_cursor(h.0, h.2, false);
-                            }
-                        }
-                        KeyCode::Char('m') => {
-                            if !active_doc.state.highlights.is_empty() {
-                                let len = active_doc.state.highlights.len();
-                                if active_doc.state.current_match == 0 {
-                                    active_doc.state.current_match = len - 1;
-                                } else {
-                                    active_doc.state.current_match -= 1;
-                                }
-                                let h = active_doc.state.highlights[active_doc.state.current_match];
-                                active_doc.adjust_cursor(h.0, h.2, false);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
             // Handle regular character input
             (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
                 match self.focus {
                     Windows::Command => {
                         match key_event.modifiers {
                             KeyModifiers::SHIFT => {
-                                self.input_buffer.insert(self.curs_x, c.to_ascii_uppercase());
-                                self.curs_x+=1;
+                                let at = col_to_byte(&self.input_buffer, self.curs_x);
+                                let ch = c.to_ascii_uppercase();
+                                self.input_buffer.insert(at, ch);
+                                self.curs_x = grapheme_count(&self.input_buffer[..at + ch.len_utf8()]);
                             },
                             KeyModifiers::NONE => {
-                                self.input_buffer.insert(self.curs_x, c);
-                                self.curs_x+=1;
+                                let at = col_to_byte(&self.input_buffer, self.curs_x);
+                                self.input_buffer.insert(at, c);
+                                self.curs_x = grapheme_count(&self.input_buffer[..at + c.len_utf8()]);
                             }
                             _ =>{}
                         }
+                        self.history_cursor = None;
+                        self.update_incremental_search();
                     },
                     Windows::Editor => {
-                        if self.documents.is_empty() { return; }
-                        let active_doc = &mut self.documents[self.active];
-                        let offset = active_doc.state.scroll_offset;
-                        match active_doc.state.selection{
-
-                            Some(s)=>{
-                                {
-                                    // Normalize selection bounds
-                                    let (y1, x1) = s.0;
-                                    let (y2, x2) = s.1;
-                                    let (start, stop) = if (y1, x1) <= (y2, x2) {
-                                        ((y1, x1), (y2, x2))
-                                    } else {
-                                        ((y2, x2), (y1, x1))
-                                    };
-
-                                    // Delete the selection and record it for undo
-                                    let deleted = active_doc.delete_selection(start, stop);
-                                    active_doc.state.undo_stack.push(EditOp::DeleteSelection {
-                                        start,
-                                        stop,
-                                        selection: deleted,
-                                        applied: false,
-                                    });
-
-                                    // Move cursor to the start of the deleted region and clear selection
-                                    active_doc.adjust_cursor(start.0, start.1, false);
-                                    active_doc.state.selection = None;
-                                }
-                            }
-                            None =>{
-                            
-                            }
-                        }
-                        active_doc.state.undo_stack.push(EditOp::InsertChar { 
-                            line: offset + active_doc.state.curs_y,
-                            col: active_doc.state.curs_x,
-                            ch: if key_event.modifiers.contains(KeyModifiers::SHIFT) {c.to_ascii_uppercase()} else {c},
-                            applied: false});
-
-                        active_doc.insert_char(
-                            offset + active_doc.state.curs_y,
-                            active_doc.state.curs_x,
-                            if key_event.modifiers.contains(KeyModifiers::SHIFT) {c.to_ascii_uppercase()} else {c}
-                            );
-
-                        
-                        
-                        
-                        // Ensure the content vector has enough lines
-                        
+                        if self.documents.is_empty() || self.refuse_read_only() { return; }
+                        let ch = if key_event.modifiers.contains(KeyModifiers::SHIFT) {c.to_ascii_uppercase()} else {c};
+                        self.documents[self.active].type_char(ch);
                     }
                 }
             }
@@ -686,7 +647,9 @@ impl App {
             return;
         }else {
 
-            self.documents.remove(self.active);
+            let index = self.active;
+            let closed = self.documents.remove(index);
+            swap::remove_swap(&closed.file_path);
             if self.documents.is_empty() {
                 // After closing last doc, show commands/help
                 // Keep active at 0 (no docs)
@@ -694,6 +657,7 @@ impl App {
             } else {
                 self.active = self.active.saturating_sub(1);
             }
+            self.document_closed(index);
             return;
         }
     }
@@ -705,7 +669,7 @@ impl App {
         let active_doc = &self.documents[self.active];
         active_doc
             .content
-            .iter()
+            .lines()
             .enumerate()
             .map(|(index, line)| format!("{}: {}", index + 1, line))
             .collect()
@@ -725,158 +689,370 @@ impl App {
        
         if command.trim() == "o" {
             Ok(Some(Operations::Open(String::from(args.join(" ")))))
-        } else if command.trim() == "theme" {
-            Ok(Some(Operations::Open(String::from("/home/petru/.config/tpad/theme.toml"))))
-        } else if command.trim() == "set" {
-            self.show_popup(String::new(), PopupTypes::ThemeSelectPopup);
-            Ok(Some(Operations::None))
+        } else if command == "set" && !args.is_empty() {
+            Ok(Some(Operations::SetOption(args.join(" "))))
         } else if command.trim() == "setdir" {
             if args.is_empty() {
                 Err("setdir requires a path argument".into())
             } else {
                 Ok(Some(Operations::SetDefaultDir(args.join(" "))))
             }
+        } else if command.trim() == "backup" {
+            if args.is_empty() {
+                Err("backup expects 'off', 'on' or a directory".into())
+            } else {
+                Ok(Some(Operations::SetBackup(args.join(" "))))
+            }
+        } else if command.trim() == "eol" {
+            match args.first().and_then(|name| LineEnding::parse(name)) {
+                Some(line_ending) => Ok(Some(Operations::SetLineEnding(line_ending))),
+                None => Err("eol expects 'lf' or 'crlf'".into()),
+            }
+        } else if cmd.trim_start().starts_with("s/") || cmd.trim_start().starts_with("%s/") {
+            Ok(Some(Operations::Substitute(search::parse_substitute(cmd)?)))
         } else if command.trim().starts_with('/') {
-            Ok(Some(Operations::Find(String::from(command.trim().trim_start_matches("/")))))
+            // Patterns may contain spaces, take everything after the slash
+            Ok(Some(Operations::Find(cmd.trim_start()[1..].to_string())))
+        } else if command.trim() == "grep" {
+            match args.split_first() {
+                Some((pattern, dir)) => Ok(Some(Operations::Grep(pattern.to_string(), dir.join(" ")))),
+                None => Err("grep expects a pattern and an optional directory".into()),
+            }
         } else if command.trim() == "count" {
-            if let Some(word) = args.get(0) {
-                Ok(Some(Operations::WordCount((*word).to_string())))
+            if args.is_empty() {
+                Err("No pattern provided for count command".into())
             } else {
-                Err("No word provided for count command".into())
-            }
-        } else if command.trim() == "list" {
-            Ok(Some(Operations::List))
-        } else if command.trim() == "clundo" {
-            self.documents[self.active].state.undo_stack.stack.clear();
-            self.documents[self.active].state.undo_stack.cursor = 0;
-            Ok(Some(Operations::None))
-        } else if command.trim() == "q" {
-            Ok(Some(Operations::Close))
-        } else if command.trim() == "wq" {
-            self.documents[self.active].save_file()?;
-            Ok(Some(Operations::Close))
-        } else if command.trim() == "w" {
-            self.documents[self.active].save_file()?;
-            Ok(Some(Operations::None))
-        } else if command.trim() == "cl" {
-            Ok(Some(Operations::Exit))
+                Ok(Some(Operations::WordCount(args.join(" "))))
+            }
+        } else if command.trim() == "undo" {
+            match args.first().and_then(|n| n.parse::<usize>().ok()) {
+                Some(state) => Ok(Some(Operations::UndoJump(state))),
+                None => Err("undo expects a history state number".into()),
+            }
+        } else if command.trim() == "earlier" || command.trim() == "later" {
+            let steps = match args.first() {
+                Some(n) => n.parse::<isize>().map_err(|_| "earlier/later expect a number of steps")?,
+                None => 1,
+            };
+            Ok(Some(Operations::UndoTime(if command.trim() == "earlier" { -steps } else { steps })))
         } else {
             Err("Invalid command ".into())
         }
     }
 
     pub fn command_run(&mut self, cmd: &str) {
+        // Actions typed by name without arguments come from the registry
+        if let Some(action) = actions::by_name(cmd.trim()) {
+            (action.run)(self);
+            return;
+        }
         match self.command_parse(cmd) {
-            Ok(cmd) => match cmd {
-                Some(Operations::Open(file_path)) => {
-                    let result = self.open(&file_path);
-                    if let Err(e) = result {
-                        self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+            Ok(Some(op)) => self.run_operation(op),
+            Ok(None) => {}
+            Err(e) => {
+                self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+            }
+        }
+    }
+
+    pub fn run_operation(&mut self, op: Operations) {
+        match op {
+            Operations::Open(file_path) => {
+                let result = self.open(&file_path);
+                if let Err(e) = result {
+                    self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+                }
+            }
+            Operations::Find(word) => {
+                if self.documents.is_empty() {
+                    self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                } else {
+                    match search::parse_search(&word) {
+                        Ok(pattern) => {
+                            let doc = &mut self.documents[self.active];
+                            // Start from where the search was typed, not from the match it previewed
+                            let (line, col) = match &self.search_origin {
+                                Some(o) => (o.scroll_offset + o.curs_y, o.curs_x),
+                                None => (doc.state.scroll_offset + doc.state.curs_y, doc.state.curs_x),
+                            };
+                            let matches = doc.find(&pattern);
+                            doc.highlight(matches);
+                            doc.goto_match(doc.match_from(line, col));
+                            self.focus = Windows::Editor;
+                        }
+                        Err(e) => self.show_popup(e.to_string(), PopupTypes::ErrorPopup),
                     }
                 }
-                Some(Operations::Find(word)) => {
-                    if self.documents.is_empty() {
-                        self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+            }
+            Operations::Grep(pattern, dir) => match search::parse_search(&pattern) {
+                Ok(regex) => {
+                    let dir = if dir.is_empty() { self.default_dir.clone() } else { self.resolve_path(&dir) };
+                    if dir.is_dir() {
+                        self.show_grep_results(&pattern, &regex, &dir);
                     } else {
-                        let doc = &mut self.documents[self.active];
-                        let matches = doc.find(&word);
-                        doc.highlight(matches);
-                        self.focus = Windows::Editor;
+                        self.show_popup(format!("Not a directory: {}", dir.to_string_lossy()), PopupTypes::ErrorPopup);
                     }
                 }
-                Some(Operations::WordCount(word)) => {
-                    if self.documents.is_empty() {
-                        self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                Err(e) => self.show_popup(e.to_string(), PopupTypes::ErrorPopup),
+            },
+            Operations::Substitute(_) | Operations::SetLineEnding(_) if self.refuse_read_only() => {}
+            Operations::Substitute(sub) => {
+                if self.documents.is_empty() {
+                    self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                } else {
+                    let doc = &mut self.documents[self.active];
+                    let matches = doc.substitution_matches(&sub);
+                    self.focus = Windows::Editor;
+                    if matches.is_empty() {
+                        self.show_popup(format!("Pattern not found: {}", sub.pattern.as_str()), PopupTypes::ErrorPopup);
+                    } else if sub.confirm {
+                        // Everything accepted in the prompt is undone as one step
+                        doc.highlight(matches);
+                        doc.state.current_match = 0;
+                        doc.state.undo_stack.begin_group();
+                        self.substitution = Some(sub);
+                        self.popup = self.prompt_substitution();
                     } else {
-                        let msg = self.documents[self.active].word_count(&word);
-                        self.show_popup(format!("Word count is: {}", msg.to_string()), PopupTypes::InfoPopup);
+                        doc.substitute_all(&sub, &matches);
+                        doc.state.selection = None;
                     }
                 }
-                Some(Operations::Exit) => {
-                    if self.documents.is_empty() {
-                        self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
-                    } else if self.documents[self.active].state.is_dirty {
-                        self.show_popup(String::from("Save before quitting?"), PopupTypes::SaveOnClosePopup);
-                    } else {
-                        self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
+            }
+            Operations::WordCount(word) => {
+                if self.documents.is_empty() {
+                    self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                } else {
+                    match search::parse_search(&word) {
+                        Ok(pattern) => {
+                            let msg = self.documents[self.active].word_count(&pattern);
+                            self.show_popup(format!("Word count is: {}", msg), PopupTypes::InfoPopup);
+                        }
+                        Err(e) => self.show_popup(e.to_string(), PopupTypes::ErrorPopup),
                     }
                 }
-                Some(Operations::SetDefaultDir(dir)) => {
-                    // Use HOME-based resolution, not default_dir
-                    let path = self.resolve_dir_for_setdir(&dir);
-                    if let Err(e) = fs::create_dir_all(&path) {
-                        self.show_popup(format!("Failed to set dir: {}", e), PopupTypes::ErrorPopup);
-                    } else if !path.is_dir() {
-                        self.show_popup("Path is not a directory".to_string(), PopupTypes::ErrorPopup);
-                    } else {
-                        self.default_dir = path.clone();
-                        self.show_popup(
-                            format!("Default directory set to:\n{}", path.to_string_lossy()),
-                            PopupTypes::InfoPopup
-                        );
+            }
+            Operations::Exit => {
+                if self.documents.is_empty() {
+                    self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
+                } else if self.documents[self.active].state.is_dirty {
+                    self.show_popup(String::from("Save before quitting?"), PopupTypes::SaveOnClosePopup);
+                } else {
+                    self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
+                }
+            }
+            Operations::SetDefaultDir(dir) => {
+                // Use HOME-based resolution, not default_dir
+                let path = self.resolve_dir_for_setdir(&dir);
+                if let Err(e) = fs::create_dir_all(&path) {
+                    self.show_popup(format!("Failed to set dir: {}", e), PopupTypes::ErrorPopup);
+                } else if !path.is_dir() {
+                    self.show_popup("Path is not a directory".to_string(), PopupTypes::ErrorPopup);
+                } else {
+                    self.default_dir = path.clone();
+                    self.show_popup(
+                        format!("Default directory set to:\n{}", path.to_string_lossy()),
+                        PopupTypes::InfoPopup
+                    );
+                }
+            }
+            Operations::SetOption(arg) => {
+                let (option, value) = match arg.split_once('=') {
+                    Some((option, value)) => (option.trim(), value.trim()),
+                    None => (arg.trim(), ""),
+                };
+                if !arg.contains('=') {
+                    // `:set option` shows the current value
+                    match self.config.get(option) {
+                        Some(value) => self.show_popup(format!("{}={}", option, value), PopupTypes::InfoPopup),
+                        None => self.show_popup(format!("Unknown option `{}`", option), PopupTypes::ErrorPopup),
                     }
+                } else if let Err(e) = self.config.set(option, value) {
+                    self.show_popup(e, PopupTypes::ErrorPopup);
+                } else if option == "default_dir" && !value.is_empty() {
+                    self.run_operation(Operations::SetDefaultDir(value.to_string()));
+                } else if option == "backup" {
+                    self.run_operation(Operations::SetBackup(value.to_string()));
                 }
-                Some(Operations::List) => {
-                    // Pretty-print default dir with ~
-                    let dir_display = {
-                        use std::path::PathBuf;
-                        let p: PathBuf = self.default_dir.clone();
-                        let home_dir = std::env::var_os("HOME")
-                            .or_else(|| std::env::var_os("USERPROFILE"))
-                            .map(PathBuf::from);
-                        if let Some(home) = home_dir {
-                            if let Ok(stripped) = p.strip_prefix(&home) {
-                                format!("~/{}", stripped.to_string_lossy())
-                            } else {
-                                p.to_string_lossy().to_string()
-                            }
+            }
+            Operations::SetBackup(arg) => {
+                match self.backup_policy(&arg) {
+                    Ok(policy) => {
+                        let msg = match &policy {
+                            BackupPolicy::Off => "Backups disabled".to_string(),
+                            BackupPolicy::SameDir => "Backups saved next to each file as <file>~".to_string(),
+                            BackupPolicy::Dir(dir) => format!("Backups saved to:\n{}", dir.to_string_lossy()),
+                        };
+                        self.backup = policy;
+                        self.config.backup = arg;
+                        self.show_popup(msg, PopupTypes::InfoPopup);
+                    }
+                    Err(e) => self.show_popup(format!("Failed to set backup dir: {}", e), PopupTypes::ErrorPopup),
+                }
+            }
+            Operations::SetLineEnding(line_ending) => {
+                if self.documents.is_empty() {
+                    self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                } else {
+                    self.documents[self.active].set_line_ending(line_ending);
+                }
+            }
+            Operations::UndoTree => {
+                if self.documents.is_empty() {
+                    self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+                } else {
+                    self.open_undo_tree();
+                }
+            }
+            Operations::UndoJump(_) | Operations::UndoTime(_) if self.documents.is_empty() => {
+                self.show_popup("No file open. Use ': o <file>' first.".to_string(), PopupTypes::InfoPopup);
+            }
+            Operations::UndoJump(state) => {
+                if let Err(e) = self.documents[self.active].undo_jump(state) {
+                    self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+                }
+            }
+            Operations::UndoTime(steps) => {
+                if let Err(e) = self.documents[self.active].undo_time(steps) {
+                    self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+                }
+            }
+            Operations::List => {
+                // Pretty-print default dir with ~
+                let dir_display = {
+                    use std::path::PathBuf;
+                    let p: PathBuf = self.default_dir.clone();
+                    let home_dir = std::env::var_os("HOME")
+                        .or_else(|| std::env::var_os("USERPROFILE"))
+                        .map(PathBuf::from);
+                    if let Some(home) = home_dir {
+                        if let Ok(stripped) = p.strip_prefix(&home) {
+                            format!("~/{}", stripped.to_string_lossy())
                         } else {
                             p.to_string_lossy().to_string()
                         }
-                    };
-                    let commands = [
-                        format!("Default directory: {}", dir_display),
-                        "Change it: setdir <path>".to_string(),
-                        "".to_string(),
-                        "o <file>      - open file (bare name saved under default dir)".to_string(),
-                        "setdir <path> - set default directory for new files".to_string(),
-                        "theme         - open theme file".to_string(),
-                        "set           - choose a theme".to_string(),
-                        "/<pattern>    - search for a pattern".to_string(),
-                        "count <word>  - word count".to_string(),
-                        "list          - list commands".to_string(),
-                        "clundo        - clear undo history".to_string(),
-                        "q             - close buffer".to_string(),
-                        "wq            - save & close buffer".to_string(),
-                        "w             - save buffer".to_string(),
-                        "cl            - exit editor".to_string(),
-                    ];
-                    self.show_popup(commands.join("\n"), PopupTypes::InfoPopup);
-                }
-                Some(Operations::Close) => {
-                    self.close();
-                }
-                Some(Operations::Change(index)) => {
-                    if !self.documents.is_empty() {
-                        self.change(index);
+                    } else {
+                        p.to_string_lossy().to_string()
                     }
+                };
+                let commands = [
+                    format!("Default directory: {}", dir_display),
+                    "Change it: setdir <path>".to_string(),
+                    "".to_string(),
+                    "o <file>      - open file (bare name saved under default dir)".to_string(),
+                    "files         - fuzzy find a file under the default dir (Ctrl+P)".to_string(),
+                    "palette       - search and run any action (Alt+X)".to_string(),
+                    "setdir <path> - set default directory for new files".to_string(),
+                    "theme         - open theme file".to_string(),
+                    "set           - choose a theme".to_string(),
+                    "set <option>[=<value>] - show or change a setting (tab_width, soft_tabs, line_numbers, autosave, scrolloff, default_dir)".to_string(),
+                    "keys          - open the keybindings file".to_string(),
+                    "/<regex>[/iw] - search, i ignores case, w matches whole words".to_string(),
+                    "count <regex>[/iw] - count matches".to_string(),
+                    "grep <regex>[/iw] [dir] - search every file under dir (default dir if omitted)".to_string(),
+                    "[%]s/<regex>/<text>/[gciw] - replace on the line, selection or (%) document".to_string(),
+                    "eol <lf|crlf> - convert line endings".to_string(),
+                    "backup <off|on|dir> - keep a copy of the previous version on save".to_string(),
+                    "list          - list commands".to_string(),
+                    "undotree      - browse the undo history".to_string(),
+                    "undo <n>      - go to history state n".to_string(),
+                    "earlier/later [n] - step back/forward through history states".to_string(),
+                    "clundo        - clear undo history".to_string(),
+                    "q             - close buffer".to_string(),
+                    "wq            - save & close buffer".to_string(),
+                    "w             - save buffer".to_string(),
+                    "cl            - exit editor".to_string(),
+                ];
+                self.show_popup(commands.join("\n"), PopupTypes::InfoPopup);
+            }
+            Operations::Close => {
+                self.close();
+            }
+            Operations::Change(index) => {
+                if !self.documents.is_empty() {
+                    self.change(index);
                 }
-                Some(Operations::None) => {},
-                None => {},
-            },
-            Err(e) => {
-                self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
             }
+            Operations::None => {},
         }
     }
-    
 
     pub fn exit(&mut self) -> Result<(), Box<dyn Error>> {
         session::save_session(self)?;
+        session::save_history(&self.history)?;
+        // Unsaved edits the user did not discard are kept for recovery,
+        // their swap file is the only copy of them
+        swap::write_swaps(self);
+        for doc in self.documents.iter().filter(|doc| !doc.state.is_dirty) {
+            swap::remove_swap(&doc.file_path);
+        }
         self.running = false;
         Ok(())
     }
 
+    // Saves the active document unless the file changed on disk since it was
+    // loaded, in that case the user is asked first and false is returned.
+    pub fn save_active(&mut self) -> Result<bool, Box<dyn Error>> {
+        let doc = &mut self.documents[self.active];
+        if doc.is_read_only() {
+            return Err("Grep results are read-only".into());
+        }
+        if doc.state.is_dirty && doc.changed_on_disk() {
+            let msg = external_change_prompt(&doc.file_path, None);
+            self.show_popup(msg, PopupTypes::ExternalChangePopup);
+            return Ok(false);
+        }
+        doc.save_file(&self.backup)?;
+        Ok(true)
+    }
+
+    // Clean buffers follow the file on disk, dirty ones ask what to do.
+    pub fn check_disk_changes(&mut self) {
+        if self.popup.is_some() {
+            return;
+        }
+        let doc = match self.documents.get_mut(self.active) {
+            Some(doc) => doc,
+            None => return,
+        };
+        if !doc.changed_on_disk() {
+            return;
+        }
+        if doc.state.is_dirty {
+            let msg = external_change_prompt(&doc.file_path, None);
+            self.show_popup(msg, PopupTypes::ExternalChangePopup);
+        } else if let Err(e) = doc.reload() {
+            self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+        }
+    }
+
+    pub fn show_next_swap(&mut self) {
+        if let Some(swap) = self.pending_swaps.first() {
+            let msg = swap_prompt(swap, None);
+            self.show_popup(msg, PopupTypes::RecoverSwapPopup);
+        }
+    }
+
+    // Loads the swap contents into the document, opening it if needed.
+    pub fn recover_swap(&mut self, swap: &SwapFile) -> Result<(), Box<dyn Error>> {
+        let index = match self.documents.iter().position(|d| d.file_path == swap.file_path) {
+            Some(i) => i,
+            None => {
+                self.documents.push(Document::new(&swap.file_path)?);
+                self.documents.len() - 1
+            }
+        };
+        let doc = &mut self.documents[index];
+        doc.content = TextBuffer::from_text(&swap.content);
+        doc.state.curs_x = 0;
+        doc.state.curs_y = 0;
+        doc.state.scroll_offset = 0;
+        doc.state.selection = None;
+        doc.unhighlight();
+        doc.update_content();
+        self.active = index;
+        Ok(())
+    }
+
     pub fn show_empty_state_help(&mut self) {
         let dir = self.default_dir.to_string_lossy();
         let help_message = format!(
@@ -890,9 +1066,372 @@ impl App {
         self.show_popup(help_message, PopupTypes::InfoPopup);
     }
 }
+impl App {
+    // Highlights the matches of a `/` search while it is typed and jumps to the
+    // first one after the cursor. The view it started from is kept in
+    // `search_origin` until the search is run or cancelled.
+    pub fn update_incremental_search(&mut self) {
+        let Some(doc) = self.documents.get_mut(self.active) else {
+            return;
+        };
+        let Some(query) = self.input_buffer.trim_start().strip_prefix('/') else {
+            // No longer a search, put the view back
+            self.cancel_incremental_search();
+            return;
+        };
+        let origin = self.search_origin.get_or_insert_with(|| doc.search_origin());
+        let (scroll_offset, curs_y, curs_x) = (origin.scroll_offset, origin.curs_y, origin.curs_x);
+        let matches = match search::parse_search(query) {
+            Ok(pattern) => doc.find(&pattern),
+            // Half typed patterns are often invalid, they just show nothing yet
+            Err(_) => Vec::new(),
+        };
+        if matches.is_empty() {
+            doc.unhighlight();
+            doc.state.current_match = 0;
+            doc.state.scroll_offset = scroll_offset;
+            doc.state.curs_y = curs_y;
+            doc.state.curs_x = curs_x;
+        } else {
+            doc.highlight(matches);
+            doc.goto_match(doc.match_from(scroll_offset + curs_y, curs_x));
+        }
+    }
+    pub fn cancel_incremental_search(&mut self) {
+        if let (Some(origin), Some(doc)) = (self.search_origin.take(), self.documents.get_mut(self.active)) {
+            doc.restore_view(origin);
+        }
+    }
+}
+impl App {
+    // Completes the word before the cursor: a command name when it is the
+    // first word, a path for `o` (resolved like `o` does) and a directory for
+    // `setdir`. Ambiguous completions are extended as far as they agree and
+    // then listed in a popup.
+    pub fn complete_input(&mut self) {
+        let end = col_to_byte(&self.input_buffer, self.curs_x);
+        let before = &self.input_buffer[..end];
+        let start = before.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let word = &before[start..];
+        let dir_part = &word[..word.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        let candidates = match before.split_whitespace().next() {
+            _ if before[..start].trim().is_empty() => {
+                let names: Vec<&str> = actions::ACTIONS.iter().map(|a| a.name).collect();
+                complete::complete_command(&names, word)
+            }
+            _ if word == "~" => vec!["~/".to_string()],
+            Some("o") => complete::complete_path(word, &self.resolve_path(dir_part), false),
+            Some("setdir") => complete::complete_path(word, &self.resolve_dir_for_setdir(dir_part), true),
+            Some("set") if !word.contains('=') => complete::complete_command(config::OPTIONS, word)
+                .into_iter()
+                .map(|option| format!("{}=", option))
+                .collect(),
+            _ => Vec::new(),
+        };
+        match candidates.len() {
+            0 => {}
+            1 => self.insert_completion(start, end, &candidates[0]),
+            _ => {
+                let common = complete::common_prefix(&candidates);
+                if common.len() > word.len() {
+                    self.insert_completion(start, end, &common);
+                } else {
+                    self.selected_completion = 0;
+                    self.show_popup(candidates.join("\n"), PopupTypes::CompletionPopup);
+                    self.completion = Some(Completion { start, end, candidates });
+                }
+            }
+        }
+    }
+    pub fn insert_completion(&mut self, start: usize, end: usize, text: &str) {
+        self.input_buffer.replace_range(start..end, text);
+        self.curs_x = grapheme_count(&self.input_buffer[..start + text.len()]);
+        self.history_cursor = None;
+    }
+}
+impl App {
+    // Recalls the previous (or next) command or search starting with what was
+    // typed before browsing. Which history is used depends on a leading `/`.
+    pub fn history_step(&mut self, older: bool) {
+        let (kind, draft, index) = match &self.history_cursor {
+            Some(c) => (c.kind, c.draft.clone(), Some(c.index)),
+            None => (HistoryKind::of(&self.input_buffer), self.input_buffer.clone(), None),
+        };
+        let next = if older {
+            self.history.older(kind, &draft, index)
+        } else {
+            index.and_then(|i| self.history.newer(kind, &draft, i))
+        };
+        let line = match next {
+            Some(i) => {
+                let line = self.history.entries(kind)[i].clone();
+                self.history_cursor = Some(HistoryCursor { kind, index: i, draft });
+                line
+            }
+            // Nothing older, stay on the oldest match
+            None if older => return,
+            // Stepped past the newest entry, back to what was typed
+            None => {
+                if self.history_cursor.take().is_none() {
+                    return;
+                }
+                draft
+            }
+        };
+        self.input_buffer = line;
+        self.curs_x = grapheme_count(&self.input_buffer);
+        self.update_incremental_search();
+    }
+    // Keys typed during a Ctrl+R search edit the query instead of the command line.
+    fn handle_history_search(&mut self, key_event: KeyEvent) {
+        let Some(search) = self.history_search.as_mut() else {
+            return;
+        };
+        match (key_event.code, key_event.modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                // Next older match, the oldest one stays when there is none
+                if let Some(i) = self.history.find_back(search.kind, &search.query, search.found) {
+                    search.found = Some(i);
+                }
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                search.query.push(if key_event.modifiers.contains(KeyModifiers::SHIFT) { c.to_ascii_uppercase() } else { c });
+                search.found = self.history.find_back(search.kind, &search.query, None);
+            }
+            (KeyCode::Backspace, KeyModifiers::NONE) => {
+                search.query.pop();
+                search.found = self.history.find_back(search.kind, &search.query, None);
+            }
+            // The match is put on the command line to be edited or run with Enter
+            (KeyCode::Enter, KeyModifiers::NONE) => {
+                let found = search.found.and_then(|i| self.history.entries(search.kind).get(i).cloned());
+                self.history_search = None;
+                if let Some(line) = found {
+                    self.input_buffer = line;
+                    self.curs_x = grapheme_count(&self.input_buffer);
+                    self.history_cursor = None;
+                    self.update_incremental_search();
+                }
+            }
+            (KeyCode::Esc, KeyModifiers::NONE) => self.history_search = None,
+            _ => {}
+        }
+    }
+}
+impl App {
+    // Collects the matches into the results tab, replacing earlier results.
+    pub fn show_grep_results(&mut self, pattern: &str, regex: &regex::Regex, dir: &Path) {
+        let (hits, truncated) = grep::grep_dir(regex, dir);
+        let text = grep::results_text(pattern, dir, &hits, truncated);
+        let doc = Document::grep_results(&text, hits);
+        match self.documents.iter().position(|d| d.is_read_only()) {
+            Some(i) => {
+                self.documents[i] = doc;
+                self.active = i;
+            }
+            None => {
+                self.documents.push(doc);
+                self.active = self.documents.len() - 1;
+            }
+        }
+        self.focus = Windows::Editor;
+    }
+    // Opens the file of the grep result under the cursor, or switches to its
+    // tab, and puts the cursor on the match.
+    pub fn open_grep_result(&mut self) {
+        let Some(hit) = self.documents.get(self.active).and_then(|d| d.grep_hit()).cloned() else {
+            return;
+        };
+        if !Path::new(&hit.path).is_file() {
+            self.show_popup(format!("{} no longer exists", hit.path), PopupTypes::ErrorPopup);
+            return;
+        }
+        let index = match self.open_or_switch(&hit.path) {
+            Ok(index) => index,
+            Err(e) => {
+                self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+                return;
+            }
+        };
+        let doc = &mut self.documents[index];
+        // The file may have changed since the search
+        let line = hit.line.min(doc.content.len_lines() - 1);
+        doc.state.window_height = self.window_height as usize;
+        doc.state.selection = None;
+        doc.adjust_cursor(line, hit.col.min(doc.content.line_len(line)), false);
+    }
+    // Makes the tab showing `path` active, opening the file first if no tab does.
+    pub fn open_or_switch(&mut self, path: &str) -> Result<usize, Box<dyn Error>> {
+        let target = fs::canonicalize(path).ok();
+        let open = self.documents.iter().position(|d| {
+            d.file_path == path || (target.is_some() && fs::canonicalize(&d.file_path).ok() == target)
+        });
+        let index = match open {
+            Some(i) => i,
+            None => {
+                self.documents.push(Document::new(&path.to_string())?);
+                self.documents.len() - 1
+            }
+        };
+        self.active = index;
+        Ok(index)
+    }
+    // Indexes the default directory and shows the fuzzy file finder.
+    pub fn open_file_finder(&mut self) {
+        self.finder = Some(FileFinder::new(self.default_dir.clone()));
+        self.show_popup(String::new(), PopupTypes::FileFinderPopup);
+    }
+    // Edits aimed at the grep results tab are refused with a notice.
+    pub fn refuse_read_only(&mut self) -> bool {
+        let read_only = self.documents.get(self.active).is_some_and(|d| d.is_read_only());
+        if read_only {
+            self.show_popup("Grep results are read-only, press Enter on a result to open it".to_string(), PopupTypes::InfoPopup);
+        }
+        read_only
+    }
+}
+impl App {
+    // Opens the history browser with the current state selected.
+    pub fn open_undo_tree(&mut self) {
+        let states = self.documents[self.active].state.undo_stack.states();
+        self.selected_undo = states
+            .iter()
+            .position(|s| s.place == UndoPlace::Current)
+            .unwrap_or(0);
+        self.show_popup(undo_tree_text(&states), PopupTypes::UndoTreePopup);
+    }
+}
+impl App {
+    // The active document if it has a parse tree, the structural commands
+    // tell the user when it has none.
+    fn parsed_doc(&mut self) -> Option<&mut Document> {
+        if self.documents.get(self.active)?.tree.is_none() {
+            let names: Vec<&str> = parse_tree::GRAMMARS.iter().map(|g| g.name).collect();
+            self.show_popup(format!("Structural commands work in {} files", names.join(", ")), PopupTypes::InfoPopup);
+            return None;
+        }
+        self.documents.get_mut(self.active)
+    }
+    // Selects the syntax node around the selection, growing it each time.
+    pub fn select_node(&mut self) {
+        let Some(doc) = self.parsed_doc() else { return };
+        if let Some((start, end)) = doc.enclosing_node() {
+            doc.select_bytes(start, end);
+        }
+    }
+    pub fn goto_parent_node(&mut self) {
+        let Some(doc) = self.parsed_doc() else { return };
+        if let Some(byte) = doc.parent_node() {
+            doc.goto_byte(byte);
+        }
+    }
+    pub fn goto_next_node(&mut self) {
+        let Some(doc) = self.parsed_doc() else { return };
+        if let Some(byte) = doc.next_sibling_node() {
+            doc.goto_byte(byte);
+        }
+    }
+    // Lists the functions, types or headings of the document, the one the
+    // cursor is in selected.
+    pub fn open_outline(&mut self) {
+        let Some(doc) = self.parsed_doc() else { return };
+        let line = doc.state.scroll_offset + doc.state.curs_y;
+        let outline = doc.outline();
+        if outline.is_empty() {
+            self.show_popup("Nothing to outline in this file".to_string(), PopupTypes::InfoPopup);
+            return;
+        }
+        self.selected_outline = outline.iter().rposition(|item| item.line <= line).unwrap_or(0);
+        self.show_popup(parse_tree::outline_text(&outline), PopupTypes::OutlinePopup);
+        self.outline = outline;
+    }
+}
+impl App {
+    // Moves to the current match of a confirmed substitution and asks about it,
+    // finishing the substitution once every match has been answered.
+    pub fn prompt_substitution(&mut self) -> Option<Popup> {
+        let sub = self.substitution.as_ref()?;
+        let doc = self.documents.get_mut(self.active)?;
+        let index = doc.state.current_match;
+        match doc.state.highlights.get(index).copied() {
+            Some(m) => {
+                let (removed, inserted) = doc.substitution_text(sub, m).unwrap_or_default();
+                doc.adjust_cursor(m.0, m.1, false);
+                let msg = replace_prompt(&removed, &inserted, index + 1, doc.state.highlights.len());
+                Some(Popup::new(msg, PopupTypes::ReplaceConfirmPopup))
+            }
+            None => {
+                self.finish_substitution();
+                None
+            }
+        }
+    }
+    pub fn finish_substitution(&mut self) {
+        self.substitution = None;
+        if let Some(doc) = self.documents.get_mut(self.active) {
+            doc.state.undo_stack.end_group();
+            doc.unhighlight();
+            doc.state.selection = None;
+        }
+    }
+}
+pub fn replace_prompt(removed: &str, inserted: &str, number: usize, total: usize) -> String {
+    format!(
+        "Replace {:?} with {:?}? ({} of {})\n\n[y] yes  [n] no  [a] all remaining  [q] quit",
+        removed, inserted, number, total
+    )
+}
+// One line per history state: marker, state number, age and what changed.
+pub fn undo_tree_text(states: &[UndoState]) -> String {
+    states
+        .iter()
+        .map(|state| {
+            let marker = match state.place {
+                UndoPlace::Current => '>',
+                UndoPlace::Applied => '|',
+                UndoPlace::Undone => ' ',
+                UndoPlace::OtherBranch => '~',
+            };
+            let age = state.time.map(swap::describe_age).unwrap_or_default();
+            format!("{} {:>4}  {:<16} {}", marker, state.id, age, state.preview)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+pub fn external_change_prompt(file_path: &str, diff: Option<String>) -> String {
+    let mut msg = format!(
+        "{} was changed on disk and has unsaved edits here\n\n[r] reload  [o] overwrite  [d] diff  [Esc] keep editing",
+        file_path
+    );
+    if let Some(diff) = diff {
+        msg.push_str("\n\n(- on disk, + in tpad)\n");
+        msg.push_str(&diff);
+    }
+    msg
+}
+pub fn swap_prompt(swap: &SwapFile, diff: Option<String>) -> String {
+    let mut msg = format!(
+        "Unsaved changes found for {}\n(swap written {})\n\n[r] recover  [d] diff  [x] discard  [Esc] decide later",
+        swap.file_path,
+        swap::describe_age(swap.saved_at)
+    );
+    if let Some(diff) = diff {
+        msg.push_str("\n\n");
+        msg.push_str(&diff);
+    }
+    msg
+}
 pub fn move_curs(active_doc: &mut Document, direction: CursorDirection) {
     // Get the currently active document
-    if active_doc.content.len() == 0 {return;}
+    if active_doc.content.len_lines() == 0 {return;}
+    // With soft wrap Up and Down go by visual row
+    if active_doc.state.wrap_width > 0 {
+        match direction {
+            CursorDirection::Up => return move_row(active_doc, true),
+            CursorDirection::Down => return move_row(active_doc, false),
+            _ => {}
+        }
+    }
         match direction {
             CursorDirection::Left => {
                 if active_doc.state.curs_x > 0 {
@@ -902,12 +1441,12 @@ pub fn move_curs(active_doc: &mut Document, direction: CursorDirection) {
                     if active_doc.state.curs_y !=0 {
 
                         active_doc.state.curs_y = (active_doc.state.scroll_offset+ active_doc.state.curs_y).saturating_sub(1);
-                        active_doc.state.curs_x = active_doc.content[active_doc.state.curs_y].len();
+                        active_doc.state.curs_x = active_doc.content.line_len(active_doc.state.curs_y);
                     }
                 }
             }
             CursorDirection::Right => {
-                if active_doc.content[active_doc.state.scroll_offset + active_doc.state.curs_y].len() + 1
+                if active_doc.content.line_len(active_doc.state.scroll_offset + active_doc.state.curs_y) + 1
                     > active_doc.state.curs_x + 1
                 {
                     active_doc.state.curs_x += 1;
@@ -917,37 +1456,308 @@ pub fn move_curs(active_doc: &mut Document, direction: CursorDirection) {
                 if active_doc.state.curs_y > 0 {
                     active_doc.state.curs_y -= 1;
                     if active_doc.state.curs_x
-                        > active_doc.content[active_doc.state.scroll_offset + active_doc.state.curs_y].len()
+                        > active_doc.content.line_len(active_doc.state.scroll_offset + active_doc.state.curs_y)
                     {
                         active_doc.state.curs_x =
-                            active_doc.content[active_doc.state.scroll_offset + active_doc.state.curs_y].len();
+                            active_doc.content.line_len(active_doc.state.scroll_offset + active_doc.state.curs_y);
                     }
                 } else {
                     active_doc.state.scroll_offset = active_doc.state.scroll_offset.saturating_sub(1);
-                    active_doc.state.curs_x = active_doc.content[active_doc.state.curs_y + active_doc.state.scroll_offset]
-                        .len()
+                    active_doc.state.curs_x = active_doc.content.line_len(active_doc.state.curs_y + active_doc.state.scroll_offset)
                         .min(active_doc.state.curs_x);
                 }
             }
             CursorDirection::Down => {
-                if active_doc.state.curs_y < active_doc.state.window_height - 2
-                    && active_doc.state.curs_y + active_doc.state.scroll_offset < active_doc.content.len() - 1
+                if active_doc.state.curs_y < active_doc.state.window_height.saturating_sub(2)
+                    && active_doc.state.curs_y + active_doc.state.scroll_offset < active_doc.content.len_lines() - 1
                 {
                     active_doc.state.curs_y += 1;
                     if active_doc.state.curs_x
-                        > active_doc.content[active_doc.state.scroll_offset + active_doc.state.curs_y].len()
+                        > active_doc.content.line_len(active_doc.state.scroll_offset + active_doc.state.curs_y)
                     {
                         active_doc.state.curs_x =
-                            active_doc.content[active_doc.state.scroll_offset + active_doc.state.curs_y].len();
+                            active_doc.content.line_len(active_doc.state.scroll_offset + active_doc.state.curs_y);
                     }
-                } else if active_doc.state.curs_y + active_doc.state.scroll_offset < active_doc.content.len() - 1 {
+                } else if active_doc.state.curs_y + active_doc.state.scroll_offset < active_doc.content.len_lines() - 1 {
                     active_doc.state.scroll_offset += 1;
 
-                    active_doc.state.curs_x = active_doc.content[active_doc.state.curs_y + active_doc.state.scroll_offset]
-                        .len()
+                    active_doc.state.curs_x = active_doc.content.line_len(active_doc.state.curs_y + active_doc.state.scroll_offset)
                         .min(active_doc.state.curs_x);
                 }
             }
         }
-    
+}
+// Moves the cursor to the visual row above or below, keeping the cell it is
+// drawn at where the row is long enough.
+fn move_row(doc: &mut Document, up: bool) {
+    let (width, tab_width) = (doc.state.wrap_width, doc.state.tab_width);
+    let line = doc.state.scroll_offset + doc.state.curs_y;
+    let text = doc.content.line(line);
+    let starts = wrap_rows(&text, width, tab_width);
+    let row = row_of(&starts, doc.state.curs_x);
+    let cell = row_width(&text, starts[row], doc.state.curs_x, tab_width);
+    // Target line and row, None for the last row of the line
+    let (target, target_row) = if up {
+        if row > 0 {
+            (line, Some(row - 1))
+        } else if line > 0 {
+            (line - 1, None)
+        } else {
+            return;
+        }
+    } else if row + 1 < starts.len() {
+        (line, Some(row + 1))
+    } else if line + 1 < doc.content.len_lines() {
+        (line + 1, Some(0))
+    } else {
+        return;
+    };
+    let text = doc.content.line(target);
+    let starts = wrap_rows(&text, width, tab_width);
+    let row = target_row.unwrap_or(starts.len() - 1);
+    // A column at the start of the next row would be drawn there
+    let end = match starts.get(row + 1) {
+        Some(next) => next - 1,
+        None => grapheme_count(&text),
+    };
+    doc.state.curs_x = col_at_cell(&text, starts[row], end, cell, tab_width);
+    doc.state.scroll_offset = doc.state.scroll_offset.min(target);
+    doc.state.curs_y = target - doc.state.scroll_offset;
+}
+impl App {
+    // Shows the command palette with every action listed.
+    pub fn open_palette(&mut self) {
+        let mut palette = CommandPalette {
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        palette.update();
+        self.palette = Some(palette);
+        self.show_popup(String::new(), PopupTypes::CommandPalettePopup);
+    }
+    // Puts `text` on the command line for the user to finish.
+    pub fn prompt_command(&mut self, text: &str) {
+        self.focus = Windows::Command;
+        self.input_buffer = text.to_string();
+        self.curs_x = grapheme_count(text);
+        self.history_cursor = None;
+    }
+    pub fn quit(&mut self) {
+        if self.documents.is_empty() {
+            // Show help instead of exiting when no docs are open
+            self.show_empty_state_help();
+        } else if self.documents[self.active].state.is_dirty {
+            self.show_popup(String::from("Save before quitting?"), PopupTypes::SaveOnClosePopup);
+        } else {
+            self.exit().unwrap_or_else(|e| self.show_popup(e.to_string(), PopupTypes::ErrorPopup));
+        }
+    }
+    pub fn write_active(&mut self) {
+        if self.documents.is_empty() {
+            self.show_popup("No file to save. Use ': o <file>' to open one.".to_string(), PopupTypes::InfoPopup);
+            return;
+        }
+        match self.save_active() {
+            Ok(true) => {
+                self.theme = Theme::load();
+                if Path::new(&self.documents[self.active].file_path) == keymap::get_keybindings_file_path() {
+                    self.reload_keymap();
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.show_popup(e.to_string(), PopupTypes::ErrorPopup),
+        }
+    }
+    // Rereads keybindings.toml, problems are listed in a popup and the
+    // bindings that were valid still apply.
+    pub fn reload_keymap(&mut self) {
+        let (keymap, errors) = Keymap::load();
+        self.keymap = keymap;
+        self.pending_keys.clear();
+        if !errors.is_empty() {
+            self.show_popup(format!("keybindings.toml:\n{}", errors.join("\n")), PopupTypes::ErrorPopup);
+        }
+    }
+    pub fn write_and_close(&mut self) {
+        if self.documents.is_empty() {
+            return;
+        }
+        match self.save_active() {
+            Ok(true) => self.close(),
+            Ok(false) => {}
+            Err(e) => self.show_popup(e.to_string(), PopupTypes::ErrorPopup),
+        }
+    }
+    pub fn clear_undo(&mut self) {
+        if !self.documents.is_empty() {
+            self.documents[self.active].state.undo_stack.clear();
+        }
+    }
+    pub fn prev_tab(&mut self) {
+        if !self.documents.is_empty() && self.active > 0 {
+            self.active -= 1;
+        }
+    }
+    pub fn next_tab(&mut self) {
+        if !self.documents.is_empty() && self.active < self.documents.len() - 1 {
+            self.active += 1;
+        }
+    }
+    // Tabs are numbered from 1 like their labels.
+    pub fn goto_tab(&mut self, number: usize) {
+        if number > 0 && number <= self.documents.len() {
+            self.change(number - 1);
+        }
+    }
+    pub fn copy_selection(&mut self) {
+        if self.documents.is_empty() {
+            return;
+        }
+        let active_doc = &self.documents[self.active];
+        if let Some(sel) = active_doc.state.selection {
+            let copy_buffer = active_doc.content.slice(sel.0, sel.1);
+            self.clipboard = ClipboardContext::new().unwrap();
+            self.clipboard.set_contents(copy_buffer.to_owned()).unwrap();
+        }
+    }
+    pub fn paste(&mut self) {
+        let text = self.clipboard.get_contents().unwrap_or_default();
+        self.insert_text(text);
+    }
+    // Tab in the editor: spaces up to the next indent stop with soft tabs, a tab
+    // character otherwise. The file's .editorconfig wins over config.toml.
+    pub fn insert_indent(&mut self) {
+        let Some(doc) = self.documents.get(self.active) else {
+            return;
+        };
+        let (hard_tabs, width) = doc.editorconfig.indent(&self.config);
+        let text = if hard_tabs {
+            "\t".to_string()
+        } else {
+            let line = doc.content.line(doc.state.scroll_offset + doc.state.curs_y);
+            let at = view_width(&line, doc.state.curs_x, doc.editorconfig.tab_width(&self.config));
+            " ".repeat(width - at % width)
+        };
+        self.insert_text(text);
+    }
+    // Inserts `text` at the cursor, replacing the selection if there is one.
+    pub fn insert_text(&mut self, text: String) {
+        if self.documents.is_empty() || self.refuse_read_only() {
+            return;
+        }
+        let active_doc = &mut self.documents[self.active];
+        let offset = active_doc.state.scroll_offset;
+        // Replacing a selection and inserting is a single undo step
+        active_doc.state.undo_stack.begin_group();
+
+        // If there is a selection, delete it first
+        let (insert_line, insert_col) = if let Some(sel) = active_doc.state.selection.take() {
+            // normalize selection bounds
+            let ((y1, x1), (y2, x2)) = (sel.0, sel.1);
+            let (start, stop) = if (y1, x1) <= (y2, x2) {
+                ((y1, x1), (y2, x2))
+            } else {
+                ((y2, x2), (y1, x1))
+            };
+            // delete the selected range
+            let deleted = active_doc.delete_selection(start, stop);
+            active_doc.state.undo_stack.push(
+                EditOp::DeleteSelection {
+                    start,
+                    stop,
+                    selection: deleted,
+                    applied: false,
+                }
+            );
+            // move cursor to start of deleted region
+            active_doc.adjust_cursor(start.0, start.1, false);
+            // start paste at that position
+            start
+        } else {
+            // no selection: paste at current cursor
+            (offset + active_doc.state.curs_y, active_doc.state.curs_x)
+        };
+
+        // Now perform the insert
+        if insert_line < active_doc.content.len_lines() {
+            let lines: Vec<&str> = text.split('\n').collect();
+            // compute stop position
+            let stop = if lines.len() > 1 {
+                (insert_line + lines.len() - 1, grapheme_count(lines.last().unwrap()))
+            } else {
+                (insert_line, insert_col + grapheme_count(lines[0]))
+            };
+            // insert the text
+            active_doc.insert_selection((insert_line, insert_col), text.clone());
+            // record for undo
+            active_doc.state.undo_stack.push(
+                EditOp::InsetSelection {
+                    applied: false,
+                    start: (insert_line, insert_col),
+                    stop,
+                    selection: text,
+                }
+            );
+            // move cursor to end of inserted text
+            active_doc.adjust_cursor(stop.0, stop.1, false);
+        }
+        active_doc.state.undo_stack.end_group();
+    }
+    // Saves every modified file that did not change on disk meanwhile, those
+    // are left for the user to resolve.
+    pub fn autosave(&mut self) {
+        let mut error = None;
+        for doc in &mut self.documents {
+            if !doc.state.is_dirty || doc.is_read_only() || doc.changed_on_disk() {
+                continue;
+            }
+            if let Err(e) = doc.save_file(&self.backup) {
+                error = Some(format!("Autosave of '{}' failed: {}", doc.file_path, e));
+            }
+        }
+        if let Some(e) = error {
+            self.show_popup(e, PopupTypes::ErrorPopup);
+        }
+    }
+    pub fn undo_step(&mut self) {
+        if self.documents.is_empty() || self.refuse_read_only() {
+            return;
+        }
+        if let Err(e) = self.documents[self.active].undo() {
+            self.show_popup(e.to_string(), PopupTypes::ErrorPopup);
+        }
+    }
+    pub fn redo_step(&mut self) {
+        if self.documents.is_empty() || self.refuse_read_only() {
+            return;
+        }
+        self.documents[self.active].redo();
+    }
+    pub fn next_match(&mut self) {
+        let Some(active_doc) = self.documents.get_mut(self.active) else {
+            return;
+        };
+        if active_doc.state.find_active && !active_doc.state.highlights.is_empty() {
+            let len = active_doc.state.highlights.len();
+            active_doc.state.current_match = (active_doc.state.current_match + 1) % len;
+            let h = active_doc.state.highlights[active_doc.state.current_match];
+            active_doc.adjust_cursor(h.0, h.2, false);
+        }
+    }
+    pub fn prev_match(&mut self) {
+        let Some(active_doc) = self.documents.get_mut(self.active) else {
+            return;
+        };
+        if active_doc.state.find_active && !active_doc.state.highlights.is_empty() {
+            let len = active_doc.state.highlights.len();
+            if active_doc.state.current_match == 0 {
+                active_doc.state.current_match = len - 1;
+            } else {
+                active_doc.state.current_match -= 1;
+            }
+            let h = active_doc.state.highlights[active_doc.state.current_match];
+            active_doc.adjust_cursor(h.0, h.2, false);
+        }
+    }
 }
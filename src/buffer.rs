@@ -0,0 +1,483 @@
+use ropey::{Rope, RopeSlice};
+use tree_sitter::{InputEdit, Point};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Rope-backed text storage for a Document.
+// Lines are addressed by index and columns by grapheme offset within the line,
+// the line break itself is never part of a line.
+pub struct TextBuffer {
+    rope: Rope,
+    // First line changed since `take_edited_from` was last called
+    edited_from: Option<usize>,
+    // Edits since `take_edits` was last called, None once the whole text was replaced
+    edits: Option<Vec<InputEdit>>,
+}
+
+impl TextBuffer {
+    pub fn new() -> TextBuffer {
+        TextBuffer {
+            rope: Rope::new(),
+            edited_from: None,
+            edits: Some(Vec::new()),
+        }
+    }
+
+    pub fn from_text(text: &str) -> TextBuffer {
+        TextBuffer {
+            rope: Rope::from_str(text),
+            edited_from: Some(0),
+            edits: None,
+        }
+    }
+
+    fn mark_edited(&mut self, line: usize) {
+        self.edited_from = Some(self.edited_from.map_or(line, |first| first.min(line)));
+    }
+
+    // The first line changed since the last call, lines before it are as they were.
+    pub fn take_edited_from(&mut self) -> Option<usize> {
+        self.edited_from.take()
+    }
+
+    // Same as `take_edited_from`, leaving it for the next caller.
+    pub fn edited_from(&self) -> Option<usize> {
+        self.edited_from
+    }
+
+    // The edits since the last call in the form tree-sitter takes them, or
+    // None when the text was replaced and has to be parsed from scratch.
+    pub fn take_edits(&mut self) -> Option<Vec<InputEdit>> {
+        self.edits.replace(Vec::new())
+    }
+
+    // Row and byte column of a byte offset, as tree-sitter counts them.
+    fn point_at(&self, byte: usize) -> Point {
+        let row = self.rope.byte_to_line(byte);
+        Point::new(row, byte - self.rope.line_to_byte(row))
+    }
+
+    // Every change to the rope goes through `rope_insert` and `rope_remove`
+    // so the edits can be handed to the parse tree.
+    fn rope_insert(&mut self, idx: usize, text: &str) {
+        let start_byte = self.rope.char_to_byte(idx);
+        let start_position = self.point_at(start_byte);
+        self.rope.insert(idx, text);
+        let new_end_byte = start_byte + text.len();
+        let edit = InputEdit {
+            start_byte,
+            old_end_byte: start_byte,
+            new_end_byte,
+            start_position,
+            old_end_position: start_position,
+            new_end_position: self.point_at(new_end_byte),
+        };
+        if let Some(edits) = &mut self.edits {
+            edits.push(edit);
+        }
+    }
+
+    fn rope_remove(&mut self, from: usize, to: usize) {
+        let start_byte = self.rope.char_to_byte(from);
+        let old_end_byte = self.rope.char_to_byte(to);
+        let start_position = self.point_at(start_byte);
+        let old_end_position = self.point_at(old_end_byte);
+        self.rope.remove(from..to);
+        let edit = InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte: start_byte,
+            start_position,
+            old_end_position,
+            new_end_position: start_position,
+        };
+        if let Some(edits) = &mut self.edits {
+            edits.push(edit);
+        }
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn len_utf16(&self) -> usize {
+        self.rope.len_utf16_cu()
+    }
+
+    // Length of a line in graphemes, without its line break.
+    pub fn line_len(&self, line: usize) -> usize {
+        grapheme_count(&self.line(line))
+    }
+
+    // Length of a line in chars, without its line break.
+    fn line_len_chars(&self, line: usize) -> usize {
+        if line >= self.len_lines() {
+            return 0;
+        }
+        let slice = self.rope.line(line);
+        let len = slice.len_chars();
+        if len > 0 && slice.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    pub fn line_slice(&self, line: usize) -> RopeSlice<'_> {
+        let slice = self.rope.line(line);
+        slice.slice(..self.line_len_chars(line))
+    }
+
+    pub fn line(&self, line: usize) -> String {
+        if line >= self.len_lines() {
+            return String::new();
+        }
+        self.line_slice(line).to_string()
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.len_lines()).map(|i| self.line(i))
+    }
+
+    pub fn grapheme_at(&self, line: usize, col: usize) -> Option<String> {
+        if line >= self.len_lines() {
+            return None;
+        }
+        self.line(line).graphemes(true).nth(col).map(|g| g.to_string())
+    }
+
+    // Absolute char index of a (line, col) position, clamped to the buffer.
+    pub fn char_index(&self, line: usize, col: usize) -> usize {
+        if line >= self.len_lines() {
+            return self.len_chars();
+        }
+        self.rope.line_to_char(line) + col_to_char(&self.line(line), col)
+    }
+
+    // Appends empty lines until `line` is a valid index.
+    pub fn ensure_line(&mut self, line: usize) {
+        if line >= self.len_lines() {
+            self.mark_edited(self.len_lines() - 1);
+        }
+        while line >= self.len_lines() {
+            let end = self.len_chars();
+            self.rope_insert(end, "\n");
+        }
+    }
+
+    // Inserts a char and returns the column right after it. A combining
+    // mark joins the grapheme before it, so this is not always `col + 1`.
+    pub fn insert_char(&mut self, line: usize, col: usize, ch: char) -> usize {
+        let text = self.line(line);
+        let prefix = &text[..col_to_byte(&text, col)];
+        let idx = self.char_index(line, col);
+        self.rope_insert(idx, ch.encode_utf8(&mut [0; 4]));
+        self.mark_edited(line);
+        grapheme_count(&format!("{}{}", prefix, ch))
+    }
+
+    pub fn insert_str(&mut self, line: usize, col: usize, text: &str) {
+        let idx = self.char_index(line, col);
+        self.rope_insert(idx, text);
+        self.mark_edited(line);
+    }
+
+    pub fn slice(&self, start: (usize, usize), stop: (usize, usize)) -> String {
+        let (from, to) = self.ordered_range(start, stop);
+        self.rope.slice(from..to).to_string()
+    }
+
+    // Removes the text between two positions and returns it.
+    pub fn remove(&mut self, start: (usize, usize), stop: (usize, usize)) -> String {
+        let (from, to) = self.ordered_range(start, stop);
+        let removed = self.rope.slice(from..to).to_string();
+        self.rope_remove(from, to);
+        self.mark_edited(start.0.min(stop.0));
+        removed
+    }
+
+    // Removes a whole line including its line break and returns its text.
+    pub fn remove_line(&mut self, line: usize) -> String {
+        if line >= self.len_lines() {
+            return String::new();
+        }
+        let text = self.line(line);
+        let start = self.rope.line_to_char(line);
+        let end = if line + 1 < self.len_lines() {
+            self.rope.line_to_char(line + 1)
+        } else {
+            self.len_chars()
+        };
+        // The last line has no break of its own, take the one before it instead.
+        let start = if end == self.len_chars() && line > 0 {
+            start - 1
+        } else {
+            start
+        };
+        self.rope_remove(start, end);
+        self.mark_edited(line.saturating_sub(1));
+        text
+    }
+
+    // Joins `line` with the line that follows it.
+    pub fn join_line(&mut self, line: usize) {
+        if line + 1 >= self.len_lines() {
+            return;
+        }
+        let idx = self.rope.line_to_char(line) + self.line_len_chars(line);
+        self.rope_remove(idx, idx + 1);
+        self.mark_edited(line);
+    }
+
+    pub fn split_line(&mut self, line: usize, col: usize) {
+        let idx = self.char_index(line, col);
+        self.rope_insert(idx, "\n");
+        self.mark_edited(line);
+    }
+
+    // Byte offset of a (line, col) position, clamped to the buffer.
+    pub fn byte_index(&self, line: usize, col: usize) -> usize {
+        self.rope.char_to_byte(self.char_index(line, col))
+    }
+
+    // (line, col) position of a byte offset.
+    pub fn position_at_byte(&self, byte: usize) -> (usize, usize) {
+        let byte = byte.min(self.len_bytes());
+        let line = self.rope.byte_to_line(byte);
+        let text = self.line(line);
+        (line, byte_to_col(&text, byte - self.rope.line_to_byte(line)))
+    }
+
+    pub fn byte_slice(&self, from: usize, to: usize) -> String {
+        self.rope.byte_slice(from..to).to_string()
+    }
+
+    // The text from `byte` to the end of the rope chunk holding it, empty past
+    // the end. This is how the parser reads the buffer without copying it.
+    pub fn chunk_at_byte(&self, byte: usize) -> &[u8] {
+        if byte >= self.len_bytes() {
+            return &[];
+        }
+        let (chunk, start, _, _) = self.rope.chunk_at_byte(byte);
+        &chunk.as_bytes()[byte - start..]
+    }
+
+    pub fn write_to<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+        self.rope.write_to(writer)
+    }
+
+    fn ordered_range(&self, start: (usize, usize), stop: (usize, usize)) -> (usize, usize) {
+        let a = self.char_index(start.0, start.1);
+        let b = self.char_index(stop.0, stop.1);
+        (a.min(b), a.max(b))
+    }
+}
+
+impl Default for TextBuffer {
+    fn default() -> Self {
+        TextBuffer::new()
+    }
+}
+
+impl std::fmt::Display for TextBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.rope.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn grapheme_count(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+// Number of chars covered by the first `col` graphemes of `text`.
+pub fn col_to_char(text: &str, col: usize) -> usize {
+    text.graphemes(true).take(col).map(|g| g.chars().count()).sum()
+}
+
+// Byte offset where the grapheme at `col` starts, or the end of `text`.
+pub fn col_to_byte(text: &str, col: usize) -> usize {
+    text.grapheme_indices(true)
+        .nth(col)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len())
+}
+
+// Grapheme column of a byte offset inside `text`.
+pub fn byte_to_col(text: &str, byte: usize) -> usize {
+    text.grapheme_indices(true)
+        .take_while(|(i, _)| *i < byte)
+        .count()
+}
+
+// Terminal cells taken by the first `col` graphemes of `text`.
+pub fn display_width(text: &str, col: usize) -> usize {
+    text.graphemes(true).take(col).map(|g| g.width()).sum()
+}
+
+// Cells a grapheme of document text takes when drawn at cell `at`, a tab
+// reaches the next multiple of `tab_width`.
+pub fn cell_width(grapheme: &str, at: usize, tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        tab_width - at % tab_width
+    } else {
+        grapheme.width()
+    }
+}
+
+// Like `display_width` for document text, with tabs expanded.
+pub fn view_width(text: &str, col: usize, tab_width: usize) -> usize {
+    text.graphemes(true).take(col).fold(0, |at, g| at + cell_width(g, at, tab_width))
+}
+
+// Grapheme columns where the visual rows of `text` start when it is wrapped
+// at `width` cells, the first is always 0. A row breaks after its last
+// whitespace, or mid-word when a word is wider than the row. Tabs are
+// measured from the start of their row.
+pub fn wrap_rows(text: &str, width: usize, tab_width: usize) -> Vec<usize> {
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+    let width = width.max(1);
+    let mut starts = vec![0];
+    let mut cells = 0;
+    // Column right after the last whitespace in the current row
+    let mut after_space: Option<usize> = None;
+    let mut col = 0;
+    while col < graphemes.len() {
+        let grapheme = graphemes[col];
+        let w = cell_width(grapheme, cells, tab_width);
+        if cells > 0 && cells + w > width {
+            let start = after_space.filter(|&c| c < col).unwrap_or(col);
+            starts.push(start);
+            after_space = None;
+            cells = 0;
+            col = start;
+            continue;
+        }
+        cells += w;
+        col += 1;
+        if grapheme.chars().all(char::is_whitespace) {
+            after_space = Some(col);
+        }
+    }
+    starts
+}
+
+// Index of the row in `starts` that column `col` is drawn on.
+pub fn row_of(starts: &[usize], col: usize) -> usize {
+    starts.iter().rposition(|&start| start <= col).unwrap_or(0)
+}
+
+// Cells between the start of a wrapped row and column `col` on it.
+pub fn row_width(text: &str, start: usize, col: usize, tab_width: usize) -> usize {
+    text.graphemes(true)
+        .skip(start)
+        .take(col.saturating_sub(start))
+        .fold(0, |at, g| at + cell_width(g, at, tab_width))
+}
+
+// Column drawn at cell `cell` of the wrapped row starting at `start`, or
+// `end`, the last column the cursor can take on the row, when it is shorter.
+pub fn col_at_cell(text: &str, start: usize, end: usize, cell: usize, tab_width: usize) -> usize {
+    let mut at = 0;
+    for (col, g) in text.graphemes(true).enumerate().take(end).skip(start) {
+        let w = cell_width(g, at, tab_width);
+        if at + w > cell {
+            return col;
+        }
+        at += w;
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let mut buf = TextBuffer::from_text("one\ntwo");
+        buf.insert_char(0, 3, '!');
+        buf.insert_str(1, 0, "2\n");
+        assert_eq!(buf.lines().collect::<Vec<String>>(), ["one!", "2", "two"]);
+        assert_eq!(buf.slice((0, 4), (1, 1)), "\n2");
+        assert_eq!(buf.remove((1, 1), (0, 4)), "\n2");
+        assert_eq!(buf.to_string(), "one!\ntwo");
+        buf.split_line(1, 1);
+        buf.join_line(0);
+        assert_eq!(buf.to_string(), "one!t\nwo");
+        assert_eq!(buf.remove_line(1), "wo");
+        assert_eq!(buf.to_string(), "one!t");
+        buf.ensure_line(2);
+        assert_eq!(buf.len_lines(), 3);
+        assert_eq!(buf.char_index(1, 0), 6);
+        // Past the end clamps to the end
+        assert_eq!(buf.char_index(5, 0), buf.len_chars());
+    }
+
+    #[test]
+    fn columns_are_graphemes() {
+        // "e" with a combining accent is one column but two chars and three bytes
+        let buf = TextBuffer::from_text("ae\u{301}b\n\u{1f600}x");
+        assert_eq!(buf.line_len(0), 3);
+        assert_eq!(buf.grapheme_at(0, 1).as_deref(), Some("e\u{301}"));
+        assert_eq!(buf.char_index(0, 2), 3);
+        assert_eq!(buf.char_index(1, 1), 6);
+        assert_eq!(col_to_byte("ae\u{301}b", 2), 4);
+        assert_eq!(byte_to_col("ae\u{301}b", 4), 2);
+    }
+
+    #[test]
+    fn inserting_a_combining_mark_stays_in_the_column() {
+        let mut buf = TextBuffer::from_text("ab");
+        assert_eq!(buf.insert_char(0, 1, '\u{301}'), 1);
+        assert_eq!(buf.line_len(0), 2);
+        assert_eq!(buf.insert_char(0, 2, 'c'), 3);
+    }
+
+    #[test]
+    fn edits_track_the_first_changed_line() {
+        let mut buf = TextBuffer::from_text("a\nb\nc");
+        assert_eq!(buf.take_edited_from(), Some(0));
+        buf.insert_char(2, 0, 'x');
+        buf.insert_char(1, 0, 'y');
+        assert_eq!(buf.edited_from(), Some(1));
+        assert_eq!(buf.take_edited_from(), Some(1));
+        assert_eq!(buf.take_edited_from(), None);
+    }
+
+    #[test]
+    fn byte_positions() {
+        let buf = TextBuffer::from_text("ae\u{301}b\n\u{1f600}x");
+        assert_eq!(buf.byte_index(0, 2), 4);
+        assert_eq!(buf.byte_index(1, 1), 10);
+        assert_eq!(buf.position_at_byte(10), (1, 1));
+        assert_eq!(buf.position_at_byte(4), (0, 2));
+        // Past the end clamps to the end
+        assert_eq!(buf.position_at_byte(99), (1, 2));
+        assert_eq!(buf.byte_slice(4, 10), "b\n\u{1f600}");
+    }
+
+    #[test]
+    fn wrap_rows_break_after_spaces() {
+        assert_eq!(wrap_rows("", 10, 4), [0]);
+        assert_eq!(wrap_rows("short", 10, 4), [0]);
+        assert_eq!(wrap_rows("hello big world", 10, 4), [0, 10]);
+        // A word wider than the row is cut where the row ends
+        assert_eq!(wrap_rows("abcdefghij", 4, 4), [0, 4, 8]);
+        // A tab runs to the next tab stop and is a place to break
+        assert_eq!(wrap_rows("\tab", 5, 4), [0, 1]);
+        assert_eq!(wrap_rows("a\tb", 5, 4), [0]);
+        // Wide characters never straddle two rows
+        assert_eq!(wrap_rows("a\u{1f600}\u{1f600}", 4, 4), [0, 2]);
+    }
+}
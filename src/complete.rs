@@ -0,0 +1,62 @@
+use std::{fs, path::Path};
+
+// Command names starting with `prefix`, in the order they are listed.
+pub fn complete_command(commands: &[&str], prefix: &str) -> Vec<String> {
+    commands
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Entries of `dir` whose name starts with what follows the last `/` of
+// `typed`. Candidates keep the typed directory part and directories end in
+// `/` so completion can continue into them. Hidden entries are only offered
+// once a `.` has been typed.
+pub fn complete_path(typed: &str, dir: &Path, dirs_only: bool) -> Vec<String> {
+    let (dir_part, prefix) = match typed.rfind('/') {
+        Some(i) => typed.split_at(i + 1),
+        None => ("", typed),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            // Follows symlinks, a link to a directory completes like one
+            let is_dir = entry.path().is_dir();
+            match is_dir {
+                true => Some(format!("{}{}/", dir_part, name)),
+                false if dirs_only => None,
+                false => Some(format!("{}{}", dir_part, name)),
+            }
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+// Longest text every candidate starts with.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let Some(first) = candidates.first() else {
+        return String::new();
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len);
+    }
+    first[..len].to_string()
+}
@@ -0,0 +1,146 @@
+use std::{fs, path::PathBuf};
+
+use dirs::config_dir;
+use serde::{Deserialize, Serialize};
+
+// Option names accepted by `:set`, in the order `:set` lists them.
+pub const OPTIONS: &[&str] = &["tab_width", "soft_tabs", "line_numbers", "wrap", "autosave", "scrolloff", "default_dir", "backup"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineNumbers {
+    Off,
+    On,
+    // Distance from the cursor line, which shows its own number
+    Relative,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Columns between tab stops
+    pub tab_width: usize,
+    // Tab inserts spaces up to the next tab stop instead of a tab character
+    pub soft_tabs: bool,
+    pub line_numbers: LineNumbers,
+    // Long lines continue on the next row instead of running off the edge
+    pub wrap: bool,
+    // Seconds between saves of modified files, 0 turns autosave off
+    pub autosave: u64,
+    // Lines kept visible above and below the cursor
+    pub scrolloff: usize,
+    // Where bare file names go, empty for $HOME/Documents
+    pub default_dir: String,
+    // Copy of the previous version on save: off, on for <file>~ next to it,
+    // or a directory to collect them in
+    pub backup: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tab_width: 4,
+            soft_tabs: true,
+            line_numbers: LineNumbers::On,
+            wrap: false,
+            autosave: 0,
+            scrolloff: 0,
+            default_dir: String::new(),
+            backup: "off".to_string(),
+        }
+    }
+}
+
+pub fn get_config_file_path() -> PathBuf {
+    let base_dir = config_dir().unwrap_or_else(|| PathBuf::from("."));
+    base_dir.join("tpad").join("config.toml")
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(format!("expected true or false, got `{}`", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("expected a number, got `{}`", value))
+}
+
+impl Config {
+    // The settings in config.toml, or the defaults and what is wrong with the
+    // file. A missing file is created with the defaults, a broken one is left
+    // alone for the user to fix.
+    pub fn load() -> (Config, Option<String>) {
+        let path = get_config_file_path();
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => {
+                let config = Config::default();
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                let _ = fs::write(&path, toml::to_string(&config).expect("Serialization failed"));
+                return (config, None);
+            }
+        };
+        let config: Config = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => return (Config::default(), Some(e.message().to_string())),
+        };
+        match config.validate() {
+            Ok(()) => (config, None),
+            Err(e) => (Config::default(), Some(e)),
+        }
+    }
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.tab_width) {
+            return Err(format!("tab_width must be between 1 and 16, got {}", self.tab_width));
+        }
+        if self.backup.trim().is_empty() {
+            return Err("backup must be off, on or a directory".to_string());
+        }
+        Ok(())
+    }
+    // Changes one option from its text form, as typed after `:set`.
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let mut changed = self.clone();
+        match option {
+            "tab_width" => changed.tab_width = parse_number(value)?,
+            "soft_tabs" => changed.soft_tabs = parse_bool(value)?,
+            "line_numbers" => {
+                changed.line_numbers = match value {
+                    "off" => LineNumbers::Off,
+                    "on" => LineNumbers::On,
+                    "relative" => LineNumbers::Relative,
+                    _ => return Err(format!("expected off, on or relative, got `{}`", value)),
+                }
+            }
+            "wrap" => changed.wrap = parse_bool(value)?,
+            "autosave" => changed.autosave = parse_number(value)?,
+            "scrolloff" => changed.scrolloff = parse_number(value)?,
+            "default_dir" => changed.default_dir = value.to_string(),
+            "backup" => changed.backup = value.to_string(),
+            _ => return Err(format!("Unknown option `{}`", option)),
+        }
+        changed.validate()?;
+        *self = changed;
+        Ok(())
+    }
+    // Text form of an option's current value.
+    pub fn get(&self, option: &str) -> Option<String> {
+        let value = match option {
+            "tab_width" => self.tab_width.to_string(),
+            "soft_tabs" => self.soft_tabs.to_string(),
+            "line_numbers" => format!("{:?}", self.line_numbers).to_lowercase(),
+            "wrap" => self.wrap.to_string(),
+            "autosave" => self.autosave.to_string(),
+            "scrolloff" => self.scrolloff.to_string(),
+            "default_dir" => self.default_dir.clone(),
+            "backup" => self.backup.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
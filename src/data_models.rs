@@ -1,9 +1,16 @@
-use std::{path::PathBuf, usize};
+use std::{collections::BTreeMap, path::PathBuf, time::{Instant, SystemTime}, usize};
 
 use copypasta::{ClipboardContext, ClipboardProvider};
-use ratatui::layout::Rect;
+use crossterm::event::{KeyCode, KeyModifiers};
+use regex::Regex;
+use ratatui::layout::{Direction, Rect};
 use serde::{Deserialize, Serialize};
 use crate::theme::*;
+use crate::buffer::TextBuffer;
+use crate::actions::Action;
+use crate::config::Config;
+use crate::parse_tree::Grammar;
+use crate::syntax::Language;
 pub enum CursorDirection {
     Left,
     Right,
@@ -14,10 +21,12 @@ pub enum CursorDirection {
 pub struct App {
     pub theme: Theme,
     pub selected_theme: usize,
+    pub selected_undo: usize,
     
     pub clipboard: ClipboardContext,
     pub documents: Vec<Document>,
     pub window_height: u16,
+    pub window_width: u16,
 
     pub input_buffer: String,
 
@@ -29,7 +38,92 @@ pub struct App {
     pub focus: Windows,
     pub curs_x: usize,
     pub default_dir: PathBuf,
+    pub backup: BackupPolicy,
 
+    // Swap files found at startup that still need a recover/discard decision
+    pub pending_swaps: Vec<SwapFile>,
+    pub last_swap_write: Instant,
+    // Settings from config.toml, changed at runtime with `:set`
+    pub config: Config,
+    pub last_autosave: Instant,
+
+    // Substitution waiting for y/n answers, its matches are the active document's highlights
+    pub substitution: Option<Substitution>,
+    // View of the active document before the `/` search being typed, restored on Esc
+    pub search_origin: Option<SearchOrigin>,
+
+    // Executed commands and searches, and where Up/Down or Ctrl+R are in them
+    pub history: CommandHistory,
+    pub history_cursor: Option<HistoryCursor>,
+    pub history_search: Option<HistorySearch>,
+
+    // Candidates offered by Tab when the completion was ambiguous
+    pub completion: Option<Completion>,
+    pub selected_completion: usize,
+
+    // Entries of the outline popup
+    pub outline: Vec<OutlineItem>,
+    pub selected_outline: usize,
+
+    // Open file finder popup
+    pub finder: Option<FileFinder>,
+    // Open command palette
+    pub palette: Option<CommandPalette>,
+
+    // Keys bound to actions, and the start of a key sequence typed so far
+    pub keymap: Keymap,
+    pub pending_keys: Vec<KeyChord>,
+
+    // Editor panes, how they are split, the one with the cursor and where
+    // each was last drawn
+    pub panes: Vec<Pane>,
+    pub pane_layout: PaneLayout,
+    pub focused_pane: usize,
+    pub pane_areas: Vec<Rect>,
+}
+
+// Cursor, scroll and selection of one view onto a document.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct View {
+    pub curs_x: usize,
+    pub curs_y: usize,
+    pub scroll_offset: usize,
+    pub scroll_row: usize,
+    pub h_scroll: usize,
+    pub selection: Option<((usize, usize), (usize, usize))>,
+}
+
+// An editor pane showing a document. The focused pane's view lives in the
+// `state` of its document, so everything else works on it as before, the
+// other panes keep theirs here.
+#[derive(Debug, Clone, Default)]
+pub struct Pane {
+    pub doc: usize,
+    pub view: View,
+    // Lines the document had when the view was last moved along with its edits
+    pub lines: usize,
+}
+
+// How the editor area is divided between panes.
+#[derive(Debug, Clone)]
+pub enum PaneLayout {
+    // Index into `App::panes`
+    Pane(usize),
+    Split {
+        // Horizontal puts the parts side by side
+        direction: Direction,
+        // Share of the space the first part takes
+        percent: u16,
+        first: Box<PaneLayout>,
+        second: Box<PaneLayout>,
+    },
+}
+// What to do with the previous version of a file when it is saved.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackupPolicy {
+    Off,
+    SameDir,
+    Dir(PathBuf),
 }
 pub enum Windows {
     Editor,
@@ -40,10 +134,15 @@ pub struct LayoutSnapshot {
     pub tab_area: Rect,
     pub editor_area: Rect,
     pub command_area: Rect,
+    // Area of each pane, by index
+    pub pane_areas: Vec<Rect>,
 }
 pub struct RenderContext<'a> {
     pub theme: &'a Theme,
     pub selected_theme: &'a usize,
+    pub selected_undo: &'a usize,
+    pub selected_completion: &'a usize,
+    pub selected_outline: &'a usize,
 
     pub popup: &'a Option<Popup>,
     pub documents: &'a Vec<Document>,
@@ -53,14 +152,135 @@ pub struct RenderContext<'a> {
     pub focus: &'a Windows,
     pub curs_x: &'a usize,
     pub default_dir: &'a PathBuf,
+    pub history: &'a CommandHistory,
+    pub history_search: &'a Option<HistorySearch>,
+    pub finder: &'a Option<FileFinder>,
+    pub palette: &'a Option<CommandPalette>,
+    pub keymap: &'a Keymap,
+    pub pending_keys: &'a Vec<KeyChord>,
+    pub config: &'a Config,
+    pub panes: &'a Vec<Pane>,
+    pub pane_layout: &'a PaneLayout,
+    pub focused_pane: &'a usize,
 }
 
 pub struct Document {
     pub file_path: String,
     pub permissions: String,
     pub size: u64,
-    pub content: Vec<String>, // Changed from String to Vec<String>
+    pub content: TextBuffer,
     pub state: EditorState,
+
+    // On-disk format, restored when the file is written back
+    pub line_ending: LineEnding,
+    pub trailing_newline: bool,
+    pub bom: bool,
+    pub encoding: Encoding,
+
+    // What the file looked like when it was last loaded or saved
+    pub disk: Option<DiskStamp>,
+
+    // Set for the read-only `:grep` results tab, the hit shown on each line
+    pub grep: Option<Vec<Option<GrepHit>>>,
+
+    // Indentation and format the project's .editorconfig asks for
+    pub editorconfig: EditorConfig,
+
+    // Syntax highlighting, None for languages tpad does not know
+    pub syntax: Option<Highlighter>,
+
+    // Parse tree for the structural commands, None without a grammar
+    pub tree: Option<ParseTree>,
+}
+
+// Tree-sitter parser of a document and its last parse.
+pub struct ParseTree {
+    pub grammar: &'static Grammar,
+    pub parser: tree_sitter::Parser,
+    // None until the document is first parsed
+    pub tree: Option<tree_sitter::Tree>,
+}
+
+// A function, type, heading or table listed by the outline popup.
+#[derive(Debug, Clone)]
+pub struct OutlineItem {
+    pub label: String,
+    pub line: usize,
+    pub col: usize,
+    // Number of outline items it is nested in
+    pub depth: usize,
+}
+
+// Token classes syntax highlighting colors, see `SyntaxStyle`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Keyword,
+    Type,
+    String,
+    Comment,
+    Number,
+    Constant,
+    Function,
+}
+
+// What a line starts inside of, left open by the lines before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineState {
+    Normal,
+    Comment,
+    // An open string and the delimiter that closes it
+    Str(&'static str),
+}
+
+// Tokenizer state at the start of each line, `states[i]` is valid for line i
+// as long as nothing before it was edited.
+pub struct Highlighter {
+    pub language: &'static Language,
+    pub states: Vec<LineState>,
+}
+
+// One matching line found by `:grep`, `path` is as found while walking.
+#[derive(Debug, Clone)]
+pub struct GrepHit {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
+
+pub struct DiskStamp {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    pub hash: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+// Character encoding a file is read and written in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+}
+
+// EditorConfig properties of a document, None where no .editorconfig sets them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditorConfig {
+    pub hard_tabs: Option<bool>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub line_ending: Option<LineEnding>,
+    pub encoding: Option<Encoding>,
+    pub bom: Option<bool>,
+    pub trim_trailing_whitespace: bool,
+    pub insert_final_newline: Option<bool>,
 }
 pub struct EditorState {
     pub curs_x: usize,
@@ -68,14 +288,26 @@ pub struct EditorState {
     pub is_dirty: bool,
     
     pub window_height: usize,
+    // Columns of text the view shows
+    pub window_width: usize,
+    // Columns lines wrap at, 0 when soft wrap is off
+    pub wrap_width: usize,
+    // Columns between tab stops, for measuring wrapped rows
+    pub tab_width: usize,
     pub scroll_offset: usize,
+    // Wrapped rows of the top line scrolled out of view
+    pub scroll_row: usize,
+    // Cells scrolled off the left edge, only while soft wrap is off
+    pub h_scroll: usize,
     pub find_active: bool,
 
     pub current_match: usize,
     pub highlights: Vec<(usize, usize, usize)>,
     
     pub undo_stack: UndoStack,
-    pub selection: Option<((usize, usize), (usize, usize))>
+    pub selection: Option<((usize, usize), (usize, usize))>,
+    // Edited since the swap file was last written
+    pub swap_pending: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -89,7 +321,7 @@ pub enum EditOp {
     DeleteChar {
         line: usize,
         col: usize,
-        ch: char,
+        ch: String,
         applied: bool,
     },
     SplitLine {
@@ -114,14 +346,65 @@ pub enum EditOp {
         start: (usize, usize),
         stop: (usize, usize),
         selection: String,
-    }
-    
+    },
+    // Text swapped for other text on one line, e.g. one match of `:s`
+    Replace {
+        line: usize,
+        col: usize,
+        removed: String,
+        inserted: String,
+        applied: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UndoStack {
     pub stack: Vec<EditOp>,
     pub cursor: usize,
+    // Group id of every op in `stack`, ops sharing an id are undone together
+    #[serde(default)]
+    pub groups: Vec<usize>,
+    #[serde(default)]
+    pub next_group: usize,
+    // Redo tails that were replaced by new edits, kept so no history is lost
+    #[serde(default)]
+    pub branches: Vec<UndoBranch>,
+    // When each group was started, in seconds since the epoch
+    #[serde(default)]
+    pub times: BTreeMap<usize, u64>,
+    // Open transaction started with `begin_group`, and how deeply it is nested
+    #[serde(skip)]
+    pub transaction: Option<usize>,
+    #[serde(skip)]
+    pub depth: usize,
+    // Set when the next op must not be coalesced into the last group
+    #[serde(skip)]
+    pub sealed: bool,
+}
+
+// A branch of the undo tree that is not on the stack, it continues from the
+// state reached after group `parent` (0 is the original text).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UndoBranch {
+    pub parent: usize,
+    pub stack: Vec<EditOp>,
+    pub groups: Vec<usize>,
+}
+
+// One entry of the history browser, a state is named after the group that led to it.
+pub struct UndoState {
+    pub id: usize,
+    pub time: Option<u64>,
+    pub preview: String,
+    pub place: UndoPlace,
+}
+
+#[derive(PartialEq)]
+pub enum UndoPlace {
+    Current,
+    Applied,
+    Undone,
+    OtherBranch,
 }
 
 pub enum Operations {
@@ -134,20 +417,138 @@ pub enum Operations {
     Exit,
     None,
     SetDefaultDir(String),
+    SetLineEnding(LineEnding),
+    SetBackup(String),
+    UndoTree,
+    UndoJump(usize),
+    UndoTime(isize),
+    Substitute(Substitution),
+    Grep(String, String),
+    SetOption(String),
+}
+
+// Cursor, scroll and highlights of a document when an incremental search started.
+pub struct SearchOrigin {
+    pub scroll_offset: usize,
+    pub curs_y: usize,
+    pub curs_x: usize,
+    pub find_active: bool,
+    pub current_match: usize,
+    pub highlights: Vec<(usize, usize, usize)>,
+}
+
+// A parsed `:s/pattern/replacement/flags` command.
+pub struct Substitution {
+    pub pattern: Regex,
+    pub replacement: String,
+    // `%` prefix, otherwise the selection or the cursor line
+    pub whole_doc: bool,
+    pub global: bool,
+    pub confirm: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SavedSession {
     pub saved_files: Vec<String>,
-    pub undo_bufs: Vec<UndoStack>,
+    // Older sessions stored bare stacks in `undo_bufs`, those are ignored
+    #[serde(default)]
+    pub undo_history: Vec<SavedUndo>,
     pub active: usize,
 }
 
+// An undo stack is only valid for the exact text it was recorded on.
+#[derive(Serialize, Deserialize)]
+pub struct SavedUndo {
+    pub file_path: String,
+    pub content_hash: u64,
+    pub stack: UndoStack,
+}
+
+// Command line history, kept in history.json next to the session.
+#[derive(Serialize, Deserialize, Default)]
+pub struct CommandHistory {
+    #[serde(default)]
+    pub commands: Vec<String>,
+    // Stored with their leading `/`
+    #[serde(default)]
+    pub searches: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryKind {
+    Command,
+    Search,
+}
+
+// Entry recalled with Up/Down, `draft` is what was typed before browsing
+// and only entries starting with it are offered.
+pub struct HistoryCursor {
+    pub kind: HistoryKind,
+    pub index: usize,
+    pub draft: String,
+}
+
+// A Ctrl+R search through the history, `found` is the entry it currently shows.
+pub struct HistorySearch {
+    pub kind: HistoryKind,
+    pub query: String,
+    pub found: Option<usize>,
+}
+
+// Tab completion of the word between byte offsets `start` and `end` of the command line.
+pub struct Completion {
+    pub start: usize,
+    pub end: usize,
+    pub candidates: Vec<String>,
+}
+
+// Files below `root` filtered by a fuzzy `query`, `matches` index into `files`
+// best first and `selected` indexes into `matches`.
+pub struct FileFinder {
+    pub root: PathBuf,
+    pub files: Vec<String>,
+    pub query: String,
+    pub matches: Vec<usize>,
+    pub selected: usize,
+}
+
+// A key press as the keymap stores it, see `keymap::chord`.
+pub type KeyChord = (KeyCode, KeyModifiers);
+
+// Key sequences and the actions they run, the defaults with
+// keybindings.toml applied on top.
+pub struct Keymap {
+    pub bindings: Vec<(Vec<KeyChord>, &'static Action)>,
+}
+
+// Query typed in the command palette and the actions matching it.
+pub struct CommandPalette {
+    pub query: String,
+    pub matches: Vec<usize>,
+    pub selected: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SwapFile {
+    pub file_path: String,
+    pub content: String,
+    pub saved_at: u64,
+    pub pid: u32,
+}
+
 pub enum PopupTypes {
     ErrorPopup,
     SaveOnClosePopup,
     ThemeSelectPopup,
     InfoPopup,
+    RecoverSwapPopup,
+    ExternalChangePopup,
+    UndoTreePopup,
+    ReplaceConfirmPopup,
+    CompletionPopup,
+    FileFinderPopup,
+    CommandPalettePopup,
+    OutlinePopup,
 }
 pub struct Popup {
     pub kind: PopupTypes,
@@ -0,0 +1,38 @@
+use similar::{ChangeTag, TextDiff};
+
+// Changed lines between two texts as "- old" / "+ new", capped at `max_lines`.
+pub fn diff_summary(old: &str, new: &str, max_lines: usize) -> String {
+    let diff = TextDiff::from_lines(old, new);
+
+    let changes: Vec<String> = diff
+        .iter_all_changes()
+        .filter_map(|change| {
+            let sign = match change.tag() {
+                ChangeTag::Delete => "-",
+                ChangeTag::Insert => "+",
+                ChangeTag::Equal => return None,
+            };
+            Some(format!("{} {}", sign, change.value().trim_end_matches('\n')))
+        })
+        .collect();
+
+    if changes.is_empty() {
+        return String::from("(no differences)");
+    }
+    let mut lines: Vec<String> = changes.iter().take(max_lines).cloned().collect();
+    if changes.len() > max_lines {
+        lines.push(format!("... {} more changed lines", changes.len() - max_lines));
+    }
+    lines.join("\n")
+}
+
+// Reads a file the way Document stores it: '\n' breaks and no final newline.
+pub fn read_normalized(path: &str) -> String {
+    let text = std::fs::read_to_string(path)
+        .map(|s| s.trim_start_matches('\u{feff}').replace("\r\n", "\n"))
+        .unwrap_or_default();
+    match text.strip_suffix('\n') {
+        Some(stripped) => stripped.to_string(),
+        None => text,
+    }
+}
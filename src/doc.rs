@@ -1,6 +1,8 @@
-use std::{error::Error, fs, os::unix::fs::MetadataExt};
+use std::{collections::BTreeMap, error::Error, fs, io::Write, os::unix::fs::MetadataExt, path::Path, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{app::move_curs, data_models::*};
+use regex::Regex;
+
+use crate::{app::move_curs, buffer::{byte_to_col, col_to_byte, grapheme_count, row_of, view_width, wrap_rows, TextBuffer}, data_models::*, editorconfig, grep, parse_tree, save::write_atomic, swap, syntax};
 
 impl EditorState {
     pub fn new(past_state: Option<EditorState>) -> EditorState {
@@ -12,14 +14,38 @@ impl EditorState {
                 is_dirty: false,
                 scroll_offset: 0,
                 window_height: 0,
+                window_width: 0,
+                wrap_width: 0,
+                tab_width: 4,
+                scroll_row: 0,
+                h_scroll: 0,
                 find_active: false,
                 current_match: 0,
                 highlights: Vec::new(),
                 undo_stack: UndoStack::new(None),
                 selection: None,
+                swap_pending: false,
             },
         }
     }
+    pub fn view(&self) -> View {
+        View {
+            curs_x: self.curs_x,
+            curs_y: self.curs_y,
+            scroll_offset: self.scroll_offset,
+            scroll_row: self.scroll_row,
+            h_scroll: self.h_scroll,
+            selection: self.selection,
+        }
+    }
+    pub fn set_view(&mut self, view: View) {
+        self.curs_x = view.curs_x;
+        self.curs_y = view.curs_y;
+        self.scroll_offset = view.scroll_offset;
+        self.scroll_row = view.scroll_row;
+        self.h_scroll = view.h_scroll;
+        self.selection = view.selection;
+    }
     pub fn update_selection_end(&mut self, y: usize, x: usize) {
         let ofs = self.scroll_offset;
         if let Some((start, _)) = self.selection {
@@ -40,108 +66,598 @@ impl UndoStack {
             None => UndoStack {
                 stack: Vec::new(),
                 cursor: 0,
+                groups: Vec::new(),
+                next_group: 0,
+                branches: Vec::new(),
+                times: BTreeMap::new(),
+                transaction: None,
+                depth: 0,
+                sealed: false,
             },
         }
     }
     pub fn push(&mut self, op: EditOp) {
-        if self.cursor < self.stack.len() {
-            self.stack.truncate(self.cursor);
+        self.align_groups();
+        self.stash_tail();
+        let group = match self.transaction {
+            Some(group) => group,
+            None if self.continues_run(&op) => self.groups[self.groups.len() - 1],
+            None => self.new_group(),
+        };
+        if self.groups.last() != Some(&group) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            self.times.insert(group, now);
         }
         self.stack.push(op);
+        self.groups.push(group);
         self.cursor = self.stack.len();
+        self.sealed = false;
+    }
+    // Everything pushed until the matching `end_group` is undone as one step.
+    pub fn begin_group(&mut self) {
+        if self.transaction.is_none() {
+            self.transaction = Some(self.new_group());
+        }
+        self.depth += 1;
+    }
+    pub fn end_group(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            self.transaction = None;
+            self.sealed = true;
+        }
+    }
+    pub fn clear(&mut self) {
+        *self = UndoStack::new(None);
+    }
+    pub fn group_of(&self, index: usize) -> usize {
+        // Stacks restored from older sessions have no groups, every op stands alone
+        self.groups.get(index).copied().unwrap_or(usize::MAX - index)
+    }
+    fn new_group(&mut self) -> usize {
+        self.next_group += 1;
+        self.next_group
+    }
+    fn align_groups(&mut self) {
+        while self.groups.len() < self.stack.len() {
+            let group = self.new_group();
+            self.groups.push(group);
+        }
+    }
+    // The history state the text is in, 0 when every edit is undone.
+    pub fn current_state(&self) -> usize {
+        match self.cursor {
+            0 => 0,
+            cursor => self.group_of(cursor - 1),
+        }
+    }
+    // Moves the undone ops after the cursor into a branch instead of dropping them.
+    fn stash_tail(&mut self) {
+        if self.cursor >= self.stack.len() {
+            return;
+        }
+        let branch = UndoBranch {
+            parent: self.current_state(),
+            stack: self.stack.split_off(self.cursor),
+            groups: self.groups.split_off(self.cursor.min(self.groups.len())),
+        };
+        self.branches.push(branch);
+    }
+    // Stack position right after the last op of group `id`.
+    fn state_end(&self, id: usize) -> Option<usize> {
+        if id == 0 {
+            return Some(0);
+        }
+        self.groups.iter().rposition(|g| *g == id).map(|i| i + 1)
+    }
+    // Every history state, newest first.
+    pub fn states(&self) -> Vec<UndoState> {
+        let current = self.current_state();
+        let mut states: Vec<UndoState> = Vec::new();
+        let mut collect = |stack: &[EditOp], groups: &[usize], on_stack: bool| {
+            let mut start = 0;
+            while start < stack.len() {
+                let id = groups.get(start).copied().unwrap_or(usize::MAX - start);
+                let len = groups[start..].iter().take_while(|g| **g == id).count().max(1);
+                let place = if !on_stack {
+                    UndoPlace::OtherBranch
+                } else if id == current {
+                    UndoPlace::Current
+                } else if start + len <= self.cursor {
+                    UndoPlace::Applied
+                } else {
+                    UndoPlace::Undone
+                };
+                states.push(UndoState {
+                    id,
+                    time: self.times.get(&id).copied(),
+                    preview: describe_ops(&stack[start..start + len]),
+                    place,
+                });
+                start += len;
+            }
+        };
+        collect(&self.stack, &self.groups, true);
+        for branch in &self.branches {
+            collect(&branch.stack, &branch.groups, false);
+        }
+        states.push(UndoState {
+            id: 0,
+            time: None,
+            preview: "original text".to_string(),
+            place: if current == 0 { UndoPlace::Current } else { UndoPlace::Applied },
+        });
+        states.sort_by_key(|s| std::cmp::Reverse(s.id));
+        states
+    }
+    // Typing and backspacing are grouped per word: a run continues while the
+    // edits stay adjacent and breaks when a new word starts after whitespace.
+    fn continues_run(&self, op: &EditOp) -> bool {
+        if self.sealed || self.cursor != self.stack.len() {
+            return false;
+        }
+        match (self.stack.last(), op) {
+            (
+                Some(EditOp::InsertChar { line: prev_line, col: prev_col, ch: prev, .. }),
+                EditOp::InsertChar { line, col, ch, .. },
+            ) => {
+                prev_line == line && prev_col + 1 == *col && (!prev.is_whitespace() || ch.is_whitespace())
+            }
+            (
+                Some(EditOp::DeleteChar { line: prev_line, col: prev_col, ch: prev, .. }),
+                EditOp::DeleteChar { line, col, ch, .. },
+            ) => {
+                let blank = |s: &String| s.chars().all(char::is_whitespace);
+                prev_line == line && col + 1 == *prev_col && (!blank(prev) || blank(ch))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl LineEnding {
+    // Uses the first line break in the text, files without one default to LF.
+    pub fn detect(text: &str) -> LineEnding {
+        match text.find('\n') {
+            Some(i) if i > 0 && text.as_bytes()[i - 1] == b'\r' => LineEnding::Crlf,
+            _ => LineEnding::Lf,
+        }
+    }
+    pub fn parse(name: &str) -> Option<LineEnding> {
+        match name.to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Some(LineEnding::Lf),
+            "crlf" | "dos" => Some(LineEnding::Crlf),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+        }
+    }
+}
+
+impl Encoding {
+    pub fn decode(&self, bytes: Vec<u8>) -> Result<String, String> {
+        let units = |to_u16: fn([u8; 2]) -> u16| -> Result<String, String> {
+            if !bytes.len().is_multiple_of(2) {
+                return Err("odd number of bytes".to_string());
+            }
+            let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| to_u16([pair[0], pair[1]])).collect();
+            String::from_utf16(&units).map_err(|e| e.to_string())
+        };
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes).map_err(|e| e.to_string()),
+            // Every byte is the code point of the same number
+            Encoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Utf16Le => units(u16::from_le_bytes),
+            Encoding::Utf16Be => units(u16::from_be_bytes),
+        }
+    }
+    // Fails on characters Latin-1 has no byte for, rather than dropping them.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(c).map_err(|_| format!("'{}' can't be written as latin1", c)))
+                .collect(),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "latin1",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+        }
     }
 }
 
 impl Document {
     pub fn new(file_path: &String) -> Result<Document, Box<dyn Error>> {
         use std::io::ErrorKind;
-        let contents = match fs::read_to_string(file_path) {
-            Ok(s) => s,
+        let bytes = match fs::read(file_path) {
+            Ok(b) => b,
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 fs::File::create(file_path)?;
                 
-                String::new()
+                Vec::new()
             }
             Err(e) => return Err(Box::new(e)),
         };
         let metadata = fs::metadata(file_path)?;
         
         let size = metadata.size();
-        let lines = contents.lines().map(|line| line.to_string()).collect::<Vec<_>>();
+        let disk = DiskStamp::new(&metadata, &bytes);
+
         let permissions = permission_string(metadata.mode(), metadata.is_dir());
-        Ok(Document {
+        let mut doc = Document {
             file_path: file_path.clone(),
             permissions,
             size: size,
-            content: lines,
+            content: TextBuffer::new(),
             state: EditorState::new(None),
-        })
+            line_ending: LineEnding::Lf,
+            trailing_newline: false,
+            bom: false,
+            encoding: Encoding::Utf8,
+            disk: Some(disk),
+            grep: None,
+            editorconfig: editorconfig::resolve(Path::new(file_path)),
+            syntax: None,
+            tree: None,
+        };
+        doc.load_bytes(bytes)?;
+        doc.syntax = syntax::detect(Path::new(file_path), &doc.content.line(0)).map(Highlighter::new);
+        doc.tree = parse_tree::detect(Path::new(file_path)).and_then(ParseTree::new);
+        Ok(doc)
+    }
+    // The `:grep` results tab. It has no file behind it and cannot be edited,
+    // `hits` holds the result shown on each line after the summary.
+    pub fn grep_results(text: &str, hits: Vec<GrepHit>) -> Document {
+        let content = TextBuffer::from_text(text);
+        let header = content.len_lines() - hits.len();
+        let mut lines: Vec<Option<GrepHit>> = vec![None; header];
+        lines.extend(hits.into_iter().map(Some));
+        Document {
+            file_path: grep::GREP_TAB.to_string(),
+            permissions: "read-only".to_string(),
+            size: text.len() as u64,
+            content,
+            state: EditorState::new(None),
+            line_ending: LineEnding::Lf,
+            trailing_newline: false,
+            bom: false,
+            encoding: Encoding::Utf8,
+            disk: None,
+            grep: Some(lines),
+            editorconfig: EditorConfig::default(),
+            syntax: None,
+            tree: None,
+        }
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.grep.is_some()
+    }
+    // The `:grep` result on the cursor line, if this is a results tab.
+    pub fn grep_hit(&self) -> Option<&GrepHit> {
+        let line = self.state.scroll_offset + self.state.curs_y;
+        self.grep.as_ref()?.get(line)?.as_ref()
+    }
+    fn load_bytes(&mut self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.encoding = self.editorconfig.encoding.unwrap_or_default();
+        let mut contents = self.encoding.decode(bytes)
+            .map_err(|e| format!("'{}' is not valid {}: {}", self.file_path, self.encoding.label(), e))?;
+
+        // Remember the on-disk format, then keep only '\n' breaks in the buffer
+        self.bom = contents.starts_with('\u{feff}');
+        if self.bom {
+            contents.remove(0);
+        }
+        self.line_ending = LineEnding::detect(&contents);
+        self.trailing_newline = contents.ends_with('\n');
+        let mut lines = contents.replace("\r\n", "\n");
+        if self.trailing_newline {
+            lines.pop();
+        }
+        self.content = TextBuffer::from_text(&lines);
+
+        // What .editorconfig asks for wins, the file is converted on the next save
+        if let Some(line_ending) = self.editorconfig.line_ending {
+            self.line_ending = line_ending;
+        }
+        if let Some(bom) = self.editorconfig.bom {
+            self.bom = bom;
+        }
+        if let Some(final_newline) = self.editorconfig.insert_final_newline {
+            self.trailing_newline = final_newline;
+        }
+        Ok(())
+    }
+    // Replaces the buffer with the current file contents, dropping unsaved edits.
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes = fs::read(&self.file_path)?;
+        let metadata = fs::metadata(&self.file_path)?;
+        let disk = DiskStamp::new(&metadata, &bytes);
+        self.load_bytes(bytes)?;
+        self.disk = Some(disk);
+        self.size = metadata.size();
+
+        // Recorded edits point into the old text
+        self.state.undo_stack = UndoStack::new(None);
+        self.state.selection = None;
+        self.unhighlight();
+        let last_line = self.content.len_lines() - 1;
+        if self.state.scroll_offset > last_line {
+            self.state.scroll_offset = last_line;
+        }
+        let line = (self.state.scroll_offset + self.state.curs_y).min(last_line);
+        self.state.curs_y = line - self.state.scroll_offset;
+        self.state.curs_x = self.state.curs_x.min(self.content.line_len(line));
+        self.state.is_dirty = false;
+        self.state.swap_pending = false;
+        swap::remove_swap(&self.file_path);
+        Ok(())
+    }
+    pub fn buffer_hash(&self) -> u64 {
+        content_hash(self.content.to_string().as_bytes())
+    }
+    // True when the file on disk no longer matches what was loaded or saved.
+    pub fn changed_on_disk(&mut self) -> bool {
+        let stamp = match &self.disk {
+            Some(stamp) => stamp,
+            None => return false,
+        };
+        let metadata = match fs::metadata(&self.file_path) {
+            Ok(m) => m,
+            Err(_) => return false,
+        };
+        if metadata.len() == stamp.len && metadata.modified().ok() == stamp.modified {
+            return false;
+        }
+        // Only the timestamp moved (touch, checkout of the same content), keep the buffer
+        match fs::read(&self.file_path) {
+            Ok(bytes) if content_hash(&bytes) == stamp.hash => {
+                self.disk = Some(DiskStamp::new(&metadata, &bytes));
+                false
+            }
+            Ok(_) => true,
+            Err(_) => false,
+        }
     }
     pub fn update_content(&mut self) {
-        // Ensure that empty lines are preserved
         self.state.is_dirty = true;
-        let new_content: Vec<String> = {
-            let mut new = Vec::new();
-            for line in &self.content {
-                if line.is_empty(){
-                    new.push(String::new());
-                }
-                else{
-                    if line.contains('\n'){
-                        for spl in line.split('\n'){
-                            new.push(spl.to_string());
-                        }
-                    }
-                    else{
-                        new.push(line.to_owned());
-                    }
-                }
-                
-            }
-            new
+        self.state.swap_pending = true;
+        // The buffer tracks its own length, no need to re-join the lines
+        self.size = self.encoded_len();
+    }
+    // Size of the document once written with its line ending, BOM and final newline.
+    pub fn encoded_len(&self) -> u64 {
+        let breaks = self.content.len_lines() - 1 + self.trailing_newline as usize;
+        let extra = match self.line_ending {
+            LineEnding::Lf => 0,
+            LineEnding::Crlf => breaks,
         };
-
-        self.content = new_content;
-        // Update the file size based on the new content
-        self.size = self.content.join("\n").len() as u64;
+        // Line breaks are ASCII, they take one unit of the encoding per char
+        let (text, unit, bom) = match self.encoding {
+            Encoding::Utf8 => (self.content.len_bytes(), 1, 3),
+            Encoding::Latin1 => (self.content.len_chars(), 1, 1),
+            Encoding::Utf16Le | Encoding::Utf16Be => (2 * self.content.len_utf16(), 2, 2),
+        };
+        let bom = if self.bom { bom } else { 0 };
+        (text + (self.trailing_newline as usize + extra) * unit + bom) as u64
     }
-    pub fn save_file(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn write_contents<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        if self.encoding != Encoding::Utf8 {
+            let mut text = Vec::new();
+            self.write_text(&mut text)?;
+            let text = String::from_utf8(text).map_err(std::io::Error::other)?;
+            let bytes = self.encoding.encode(&text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            writer.write_all(&bytes)?;
+            return writer.flush();
+        }
+        self.write_text(writer)
+    }
+    // The text as UTF-8 with its on-disk line endings, BOM and final newline.
+    fn write_text<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        if self.bom {
+            writer.write_all("\u{feff}".as_bytes())?;
+        }
+        let ending = self.line_ending.as_str();
+        for (i, line) in self.content.lines().enumerate() {
+            if i > 0 {
+                writer.write_all(ending.as_bytes())?;
+            }
+            writer.write_all(line.as_bytes())?;
+        }
+        if self.trailing_newline {
+            writer.write_all(ending.as_bytes())?;
+        }
+        writer.flush()
+    }
+    pub fn save_file(&mut self, backup: &BackupPolicy) -> Result<(), Box<dyn Error>> {
         if self.state.is_dirty {
+            if self.editorconfig.trim_trailing_whitespace {
+                self.trim_trailing_whitespace();
+            }
 
-            let content_sring = self.content.join("\n");
             let to = self.file_path.clone();
-            fs::write(to, content_sring)?;
+            write_atomic(Path::new(&to), backup, |writer| self.write_contents(writer))?;
+            swap::remove_swap(&to);
+            if let (Ok(bytes), Ok(metadata)) = (fs::read(&to), fs::metadata(&to)) {
+                self.disk = Some(DiskStamp::new(&metadata, &bytes));
+            }
             
             self.state.is_dirty = false;
         }
 
         Ok(())
     }
-    pub fn word_count(&self, word: &str) -> u32 {
-        let mut findings: u32 = 0;
-        for line in &self.content {
-            for item in line.split_whitespace() {
-                if item.contains(word) {
-                    findings += 1;
-                }
-            }
+    // Drops spaces and tabs at the end of every line, as one undo step.
+    pub fn trim_trailing_whitespace(&mut self) {
+        let trailing: Vec<(usize, usize, String)> = (0..self.content.len_lines())
+            .filter_map(|i| {
+                let text = self.content.line(i);
+                let kept = text.trim_end_matches([' ', '\t']);
+                (kept.len() < text.len()).then(|| (i, grapheme_count(kept), text[kept.len()..].to_string()))
+            })
+            .collect();
+        if trailing.is_empty() {
+            return;
+        }
+        self.state.undo_stack.begin_group();
+        for (line, col, removed) in trailing {
+            self.replace_text(line, col, grapheme_count(&removed), "");
+            self.state.undo_stack.push(EditOp::Replace { line, col, removed, inserted: String::new(), applied: false });
+        }
+        self.state.undo_stack.end_group();
+        // The cursor may have been in the removed whitespace
+        let line = self.state.scroll_offset + self.state.curs_y;
+        self.state.curs_x = self.state.curs_x.min(self.content.line_len(line));
+    }
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.update_content();
         }
-        return findings
+    }
+    // Short description of the file format for the status bar, e.g. "CRLF BOM".
+    pub fn format_label(&self) -> String {
+        let mut label = self.line_ending.label().to_string();
+        if self.encoding != Encoding::Utf8 {
+            label = format!("{} {}", self.encoding.label(), label);
+        }
+        if self.bom {
+            label.push_str(" BOM");
+        }
+        if !self.trailing_newline {
+            label.push_str(" noeol");
+        }
+        label
+    }
+    pub fn word_count(&self, pattern: &Regex) -> u32 {
+        self.find(pattern).len() as u32
     }
 
-    pub fn find(&self, word: &str) -> Vec<(usize, usize, usize)> {
+    // Matches are reported as (line, start column, end column) in graphemes.
+    // Empty matches are skipped, there is nothing to highlight for them.
+    pub fn find(&self, pattern: &Regex) -> Vec<(usize, usize, usize)> {
         let mut results = Vec::new();
-        let word_size = word.len();
 
-        for (line_index, line) in self.content.iter().enumerate() {
-            let current_matches: Vec<(usize, usize, usize)> = line
-                .match_indices(word)
-                .map(|(start, _)| (line_index, start, start + word_size))
-                .collect();
+        for (line_index, line) in self.content.lines().enumerate() {
+            let current_matches = pattern
+                .find_iter(&line)
+                .filter(|m| !m.is_empty())
+                .map(|m| (line_index, byte_to_col(&line, m.start()), byte_to_col(&line, m.end())));
             results.extend(current_matches);
         }
         results
     }
+    // Matches a substitution applies to: the whole document, the selection or
+    // the cursor line, and only the first one per line unless it is global.
+    pub fn substitution_matches(&self, sub: &Substitution) -> Vec<(usize, usize, usize)> {
+        let cursor_line = self.state.scroll_offset + self.state.curs_y;
+        let selection = self.state.selection.map(|(a, b)| (a.min(b), a.max(b)));
+        let mut matches: Vec<(usize, usize, usize)> = self
+            .find(&sub.pattern)
+            .into_iter()
+            .filter(|&(line, start, end)| match selection {
+                _ if sub.whole_doc => true,
+                Some((from, to)) => (line, start) >= from && (line, end) <= to,
+                None => line == cursor_line,
+            })
+            .collect();
+        if !sub.global {
+            matches.dedup_by_key(|m| m.0);
+        }
+        matches
+    }
+    // The matched text and what it is replaced with, capture groups expanded.
+    pub fn substitution_text(&self, sub: &Substitution, m: (usize, usize, usize)) -> Option<(String, String)> {
+        let text = self.content.line(m.0);
+        let caps = sub.pattern.captures_at(&text, col_to_byte(&text, m.1))?;
+        let whole = caps.get(0)?;
+        let mut inserted = String::new();
+        caps.expand(&sub.replacement, &mut inserted);
+        Some((whole.as_str().to_string(), inserted))
+    }
+    // Replaces one match and returns how many graphemes its line grew by.
+    pub fn substitute_at(&mut self, sub: &Substitution, m: (usize, usize, usize)) -> isize {
+        let Some((removed, inserted)) = self.substitution_text(sub, m) else {
+            return 0;
+        };
+        let removed_len = grapheme_count(&removed);
+        let inserted_len = grapheme_count(&inserted);
+        self.replace_text(m.0, m.1, removed_len, &inserted);
+        self.state.undo_stack.push(EditOp::Replace { line: m.0, col: m.1, removed, inserted, applied: false });
+        self.adjust_cursor(m.0, m.1 + inserted_len, false);
+        inserted_len as isize - removed_len as isize
+    }
+    // Replaces every match as a single undo step, returns how many were replaced.
+    pub fn substitute_all(&mut self, sub: &Substitution, matches: &[(usize, usize, usize)]) -> usize {
+        self.state.undo_stack.begin_group();
+        // Back to front, so replacing one match never moves the ones still to do
+        for m in matches.iter().rev() {
+            self.substitute_at(sub, *m);
+        }
+        self.state.undo_stack.end_group();
+        if let Some(first) = matches.first() {
+            self.adjust_cursor(first.0, first.1, false);
+        }
+        matches.len()
+    }
+    // Swaps `len` graphemes at (line, col) for `text` on a single line.
+    pub fn replace_text(&mut self, line: usize, col: usize, len: usize, text: &str) {
+        self.content.remove((line, col), (line, col + len));
+        self.content.insert_str(line, col, text);
+        self.update_content();
+    }
+    // Index of the first highlight at or after (line, col), wrapping around to the first one.
+    pub fn match_from(&self, line: usize, col: usize) -> usize {
+        self.state
+            .highlights
+            .iter()
+            .position(|h| (h.0, h.1) >= (line, col))
+            .unwrap_or(0)
+    }
+    // Makes the highlight at `index` the current match and moves the cursor onto it.
+    pub fn goto_match(&mut self, index: usize) {
+        if let Some(h) = self.state.highlights.get(index).copied() {
+            self.state.current_match = index;
+            self.adjust_cursor(h.0, h.1, false);
+        }
+    }
+    pub fn search_origin(&self) -> SearchOrigin {
+        SearchOrigin {
+            scroll_offset: self.state.scroll_offset,
+            curs_y: self.state.curs_y,
+            curs_x: self.state.curs_x,
+            find_active: self.state.find_active,
+            current_match: self.state.current_match,
+            highlights: self.state.highlights.clone(),
+        }
+    }
+    pub fn restore_view(&mut self, origin: SearchOrigin) {
+        self.state.scroll_offset = origin.scroll_offset;
+        self.state.curs_y = origin.curs_y;
+        self.state.curs_x = origin.curs_x;
+        self.state.find_active = origin.find_active;
+        self.state.current_match = origin.current_match;
+        self.state.highlights = origin.highlights;
+    }
     pub fn highlight(&mut self, v: Vec<(usize, usize, usize)>) {
         self.state.highlights = v;
         self.state.find_active = true;
@@ -150,7 +666,91 @@ impl Document {
         self.state.highlights.clear();
         self.state.find_active = false;
     }
+    // Undoes the most recent group of edits.
     pub fn undo(&mut self) -> Result<(), Box<dyn Error>> {
+        let undo_stack = &self.state.undo_stack;
+        if undo_stack.stack.is_empty() || undo_stack.cursor == 0 {
+            return Err("No operations to undo".into());
+        }
+        let group = undo_stack.group_of(undo_stack.cursor - 1);
+        while self.state.undo_stack.cursor > 0
+            && self.state.undo_stack.group_of(self.state.undo_stack.cursor - 1) == group
+        {
+            self.undo_op()?;
+        }
+        self.state.undo_stack.sealed = true;
+        Ok(())
+    }
+    // Redoes the next group of undone edits.
+    pub fn redo(&mut self) {
+        let undo_stack = &self.state.undo_stack;
+        if undo_stack.cursor >= undo_stack.stack.len() {
+            return;
+        }
+        let group = undo_stack.group_of(undo_stack.cursor);
+        while self.state.undo_stack.cursor < self.state.undo_stack.stack.len()
+            && self.state.undo_stack.group_of(self.state.undo_stack.cursor) == group
+        {
+            self.redo_op();
+        }
+        self.state.undo_stack.sealed = true;
+    }
+    // Moves the text to any history state, switching branches if needed.
+    pub fn undo_jump(&mut self, target: usize) -> Result<(), Box<dyn Error>> {
+        let undo_stack = &mut self.state.undo_stack;
+        undo_stack.align_groups();
+        let known = target == 0
+            || undo_stack.groups.contains(&target)
+            || undo_stack.branches.iter().any(|b| b.groups.contains(&target));
+        if !known {
+            return Err(format!("No history state {}", target).into());
+        }
+        self.goto_state(target)?;
+        self.state.undo_stack.sealed = true;
+        Ok(())
+    }
+    // Steps through history states in the order they were made, across branches.
+    pub fn undo_time(&mut self, steps: isize) -> Result<(), Box<dyn Error>> {
+        let mut ids: Vec<usize> = self.state.undo_stack.states().iter().map(|s| s.id).collect();
+        ids.reverse();
+        let current = self.state.undo_stack.current_state();
+        let pos = ids.iter().position(|id| *id == current).unwrap_or(0) as isize;
+        let target = (pos + steps).clamp(0, ids.len() as isize - 1) as usize;
+        self.undo_jump(ids[target])
+    }
+    fn goto_state(&mut self, target: usize) -> Result<(), Box<dyn Error>> {
+        if self.state.undo_stack.state_end(target).is_none() {
+            // Reach the fork point first, then swap the branch onto the stack
+            let parent = self
+                .state
+                .undo_stack
+                .branches
+                .iter()
+                .find(|b| b.groups.contains(&target))
+                .map(|b| b.parent)
+                .ok_or("History state not found")?;
+            self.goto_state(parent)?;
+            let undo_stack = &mut self.state.undo_stack;
+            let index = undo_stack
+                .branches
+                .iter()
+                .position(|b| b.groups.contains(&target))
+                .ok_or("History state not found")?;
+            let branch = undo_stack.branches.remove(index);
+            undo_stack.stash_tail();
+            undo_stack.stack.extend(branch.stack);
+            undo_stack.groups.extend(branch.groups);
+        }
+        let end = self.state.undo_stack.state_end(target).ok_or("History state not found")?;
+        while self.state.undo_stack.cursor > end {
+            self.undo()?;
+        }
+        while self.state.undo_stack.cursor < end {
+            self.redo();
+        }
+        Ok(())
+    }
+    fn undo_op(&mut self) -> Result<(), Box<dyn Error>> {
         if self.state.undo_stack.stack.is_empty() || self.state.undo_stack.cursor == 0 {
             return Err("No operations to undo".into());
         }
@@ -161,11 +761,12 @@ impl Document {
 
         enum OpAction {
             DeleteChar { line: usize, col: usize },
-            InsertChar { line: usize, col: usize, ch: char },
+            InsertChar { line: usize, col: usize, ch: String },
             MergeLines { first_line: usize, second_line: usize },
             SplitLine { merged_line: usize, merge_point: usize },
             InsertSelection{start: (usize, usize), stop: (usize,usize), selection: String},
-            DeleteSelection{start: (usize, usize), stop:(usize, usize)}
+            DeleteSelection{start: (usize, usize), stop:(usize, usize)},
+            Replace { line: usize, col: usize, len: usize, text: String },
         }
 
         let action = {
@@ -187,7 +788,7 @@ impl Document {
                     }
                     let l = *line;
                     let c = *col;
-                    let ch = *ch;
+                    let ch = ch.clone();
                     *applied = true;
                     // For a deletion, undo by inserting the missing char.
                     OpAction::InsertChar { line: l, col: c, ch }
@@ -237,6 +838,14 @@ impl Document {
                     }
                     OpAction::DeleteSelection { start: s, stop: e }
                 }
+                EditOp::Replace { line, col, removed, inserted, applied } => {
+                    if *applied {
+                        return Ok(());
+                    }
+                    *applied = true;
+                    // Put the matched text back in place of the replacement.
+                    OpAction::Replace { line: *line, col: *col, len: grapheme_count(inserted), text: removed.clone() }
+                }
             }
         };
 
@@ -246,12 +855,12 @@ impl Document {
                 self.adjust_cursor(line, col.saturating_sub(1), false);
             }
             OpAction::InsertChar { line, col, ch } => {
-                self.insert_char(line, col, ch);
+                self.insert_selection((line, col), ch);
                 self.adjust_cursor(line, col, false);
             }
             OpAction::MergeLines { first_line, second_line } => {
                 self.merge_lines(first_line, second_line);
-                self.adjust_cursor(first_line, self.content[first_line].len(), false);
+                self.adjust_cursor(first_line, self.content.line_len(first_line), false);
             }
             OpAction::SplitLine { merged_line, merge_point } => {
                 self.split_lines(merged_line, merge_point);
@@ -264,10 +873,14 @@ impl Document {
             OpAction::DeleteSelection { start, stop } => {
                 self.delete_selection(start, stop);
                 self.adjust_cursor(start.0,start.1, false);
-                if start.0 < self.content.len(){
+                if start.0 < self.content.len_lines(){
                     move_curs(self, CursorDirection::Up);
                 }
             }
+            OpAction::Replace { line, col, len, text } => {
+                self.replace_text(line, col, len, &text);
+                self.adjust_cursor(line, col, false);
+            }
         }
 
         Ok(())
@@ -277,7 +890,7 @@ impl Document {
         
     
     
-    pub fn redo(&mut self) {
+    fn redo_op(&mut self) {
         // First, extract operation details and update the applied flag.
       
             let op_stack = &mut self.state.undo_stack;
@@ -342,6 +955,14 @@ impl Document {
                     None
                 }
             }
+            EditOp::Replace { applied, .. } => {
+                if *applied {
+                    *applied = false;
+                    Some(op.clone())
+                } else {
+                    None
+                }
+            }
             
         }
     };
@@ -349,8 +970,10 @@ impl Document {
             Some(op) =>{
                 match op {
                     EditOp::DeleteChar { line, col, .. } => {
-                        self.delete_char(line, col);
-                        self.adjust_cursor(line, col-1, false);
+                        // `col` is where the deleted grapheme was, delete_char
+                        // takes the column after it like Backspace does
+                        self.delete_char(line, col + 1);
+                        self.adjust_cursor(line, col, false);
                     }
                     EditOp::DeleteSelection { start, stop, .. } =>{
                         self.delete_selection(start, stop);
@@ -369,6 +992,10 @@ impl Document {
                         self.insert_selection(start, selection);
                         self.adjust_cursor(stop.0, stop.1, false);
                     }
+                    EditOp::Replace { line, col, removed, inserted, .. } => {
+                        self.replace_text(line, col, grapheme_count(&removed), &inserted);
+                        self.adjust_cursor(line, col, false);
+                    }
                     
                 }
             }
@@ -382,7 +1009,7 @@ impl Document {
     // Helper function to adjust the cursor position based on an absolute line and column.
     pub fn adjust_cursor(&mut self, op_line: usize, op_col: usize, add_offset: bool) {
         // If the operation line is above the current viewport, scroll up.
-        let window_height = self.state.window_height - 2;
+        let window_height = self.state.window_height.saturating_sub(2);
         if op_line < self.state.scroll_offset {
             self.state.scroll_offset = op_line;
         // If the operation line is below the visible area, scroll down.
@@ -395,62 +1022,129 @@ impl Document {
         self.state.curs_x = op_col + if add_offset { 1 } else { 0 };
     }
 
-    pub fn insert_char(&mut self, line: usize, col:usize, c:char){
+    // Scrolls just enough to keep `scrolloff` lines visible above and below
+    // the cursor, fewer near the ends of the document.
+    pub fn keep_scrolloff(&mut self, scrolloff: usize) {
+        let rows = self.state.window_height.saturating_sub(1);
+        if rows == 0 || scrolloff == 0 {
+            return;
+        }
+        let line = self.state.scroll_offset + self.state.curs_y;
+        let margin = scrolloff.min(rows.saturating_sub(1) / 2);
+        let max_top = self.content.len_lines().saturating_sub(rows);
+        let top = if line < self.state.scroll_offset + margin {
+            line.saturating_sub(margin)
+        } else if line + margin >= self.state.scroll_offset + rows {
+            (line + margin + 1 - rows).min(max_top).max(self.state.scroll_offset)
+        } else {
+            return;
+        };
+        self.state.scroll_offset = top;
+        self.state.curs_y = line - top;
+    }
+
+    // Wrapped lines can take more rows than the view has, scrolls a row at a
+    // time until the cursor's row is on screen again. Unwrapped lines scroll
+    // sideways instead to keep the cursor's column on screen.
+    pub fn keep_cursor_in_view(&mut self) {
+        if self.state.wrap_width == 0 {
+            self.state.scroll_row = 0;
+            self.keep_cursor_in_columns();
+            return;
+        }
+        self.state.h_scroll = 0;
+        let rows = self.state.window_height.saturating_sub(1);
+        if rows == 0 {
+            return;
+        }
+        let (width, tab_width) = (self.state.wrap_width, self.state.tab_width);
+        let line = self.state.scroll_offset + self.state.curs_y;
+        // Rows of each line from the top of the view down to the cursor's
+        let heights: Vec<usize> = (self.state.scroll_offset..=line)
+            .map(|l| wrap_rows(&self.content.line(l), width, tab_width).len())
+            .collect();
+        let cursor_row = row_of(&wrap_rows(&self.content.line(line), width, tab_width), self.state.curs_x);
+        // Something else may have scrolled since the row was set
+        let mut top = 0;
+        let mut row = self.state.scroll_row.min(heights[0] - 1);
+        let mut above = (heights[..heights.len() - 1].iter().sum::<usize>() + cursor_row).saturating_sub(row);
+        while above >= rows {
+            above -= 1;
+            row += 1;
+            if row == heights[top] {
+                top += 1;
+                row = 0;
+            }
+        }
+        if top == heights.len() - 1 {
+            row = row.min(cursor_row);
+        }
+        self.state.scroll_offset += top;
+        self.state.scroll_row = row;
+        self.state.curs_y = line - self.state.scroll_offset;
+    }
 
-        while self.state.curs_y >= self.content.len() {
-            self.content.push(String::new());
+    fn keep_cursor_in_columns(&mut self) {
+        let columns = self.state.window_width;
+        if columns == 0 {
+            return;
+        }
+        let line = self.content.line(self.state.scroll_offset + self.state.curs_y);
+        let cell = view_width(&line, self.state.curs_x, self.state.tab_width);
+        if cell < self.state.h_scroll {
+            self.state.h_scroll = cell;
+        } else if cell >= self.state.h_scroll + columns {
+            self.state.h_scroll = cell + 1 - columns;
         }
+    }
+
+    // Types `c` at the cursor. Typing over a selection replaces it in one undo
+    // step, other keystrokes are left to merge into words.
+    pub fn type_char(&mut self, c: char) {
+        let replacing = self.state.selection.is_some();
+        if let Some((a, b)) = self.state.selection {
+            let (start, stop) = if a <= b { (a, b) } else { (b, a) };
+            self.state.undo_stack.begin_group();
+            let deleted = self.delete_selection(start, stop);
+            self.state.undo_stack.push(EditOp::DeleteSelection { start, stop, selection: deleted, applied: false });
+            // The cursor goes to the start of the deleted text
+            self.adjust_cursor(start.0, start.1, false);
+            self.state.selection = None;
+        }
+        let line = self.state.scroll_offset + self.state.curs_y;
+        let col = self.state.curs_x;
+        self.state.undo_stack.push(EditOp::InsertChar { line, col, ch: c, applied: false });
+        self.insert_char(line, col, c);
+        if replacing {
+            self.state.undo_stack.end_group();
+        }
+    }
+
+    pub fn insert_char(&mut self, line: usize, col:usize, c:char){
+
+        self.content.ensure_line(line);
 
         // Insert the character at the cursor position
-        self.content[line].insert(col, c);
+        let next_col = self.content.insert_char(line, col, c);
 
         // Update the document content and move the cursor
         self.update_content();
-         let active_doc = self;
-        move_curs(active_doc, CursorDirection::Right);
-        active_doc.unhighlight();
+        self.state.curs_x = next_col;
+        self.unhighlight();
     }
     pub fn insert_selection(&mut self, start: (usize, usize), st: String) {
         // Ensure enough lines exist
-        while start.0 >= self.content.len() {
-            self.content.push(String::new());
-        }
-        let line_idx = start.0;
-        let col_idx = start.1;
-        // Extract the original line to split into prefix and suffix
-        let orig_line = self.content.remove(line_idx);
-        let prefix: String = orig_line.chars().take(col_idx).collect();
-        let suffix: String = orig_line.chars().skip(col_idx).collect();
-        let parts: Vec<&str> = st.split('\n').collect();
-        if parts.len() == 1 {
-            // Single-line insertion
-            self.content.insert(line_idx, format!("{}{}{}", prefix, parts[0], suffix));
-        } else {
-            // Multi-line insertion
-            let mut idx = line_idx;
-            for (i, part) in parts.iter().enumerate() {
-                let new_line = if i == 0 {
-                    format!("{}{}", prefix, part)
-                } else if i == parts.len() - 1 {
-                    format!("{}{}", part, suffix)
-                } else {
-                    part.to_string()
-                };
-                self.content.insert(idx, new_line);
-                idx += 1;
-            }
-        }
+        self.content.ensure_line(start.0);
+        self.content.insert_str(start.0, start.1, &st);
         self.update_content();
         // Remove any active highlight
         self.unhighlight();
     }
     pub fn delete_char(&mut self, line: usize, col:usize){
-        
-        if let Some(target) = self.content.get_mut(line) {
-             
+        if line < self.content.len_lines() {
             let delete_index = col;
-            if delete_index > 0 && delete_index <= target.len() {
-                target.remove(delete_index - 1);
+            if delete_index > 0 && delete_index <= self.content.line_len(line) {
+                self.content.remove((line, delete_index - 1), (line, delete_index));
                 self.update_content();
                 move_curs(self, CursorDirection::Left);
             }
@@ -464,134 +1158,77 @@ impl Document {
         } else {
             (start, stop)
         };
-        
-        if start_line == stop_line {
-            let new_start_col = start_col.min(stop_col);
-            let new_stop_col = stop_col.max(start_col);
-            if new_stop_col - new_start_col == 
-            self.content[start_line].len() 
-            {
-                let mut  deleted: String;
-                deleted = self.content[start_line].clone(); 
-                self.content.remove(start_line);
-                match self.content.get(start_line){
-                    Some(s) => {
-                        self.state.curs_x = s.len();
-                    }
-                    None =>{
-                        self.state.curs_x =0;
-                    }
-                }
-                if start_line == self.content.len(){move_curs(self, CursorDirection::Up)}
-
-                self.update_content();
-                self.state.selection = None;
-                deleted.push('\n');
-                deleted
-            }
-            else{
-                let mut deleted = Vec::new();
-                self.content[start_line]={
-                    self.content[start_line]
-                    .chars().enumerate()
-                    .filter_map(
-                        |(i, c)| {
-                            if !(new_start_col<=i && new_stop_col>i) {
-                                Some(c)
-                            }else{
-                                deleted.push(c);
-                                None
-                            }
-                        }
-                    ).collect()
-                };
-                self.state.selection = None;
-                self.state.curs_x = new_start_col;
-                deleted.iter().collect()
-            }
-        } 
-        else {
-            let mut deleted = Vec::new();
-            let add_final_endl = if stop_col == self.content[stop_line].len() {true} else {false};
 
-            let last_elem = self.content[stop_line][..stop_col].to_owned();
-            self.content[stop_line] = self.content[stop_line][stop_col..].to_string();
-            if self.content[stop_line].len() == 0{
-                self.content.remove(stop_line);
+        self.state.selection = None;
+        if start_line == stop_line
+            && stop_col - start_col == self.content.line_len(start_line)
+            && self.content.len_lines() > 1
+        {
+            // The whole line is selected, remove it together with its line break
+            let mut deleted = self.content.remove_line(start_line);
+            self.state.curs_x = self.content.line_len(start_line);
+            if start_line == self.content.len_lines() {
+                move_curs(self, CursorDirection::Up)
             }
-            let frist_elem = self.content[start_line][start_col..].to_string();
-            
-            self.content[start_line]=self.content[start_line][..start_col].to_string();
-            deleted.push(frist_elem);
-            self.content = {
-                
-                self.content.iter().enumerate().filter_map(
-                    |(i,line)|
-                    {
-                        if i>start_line && i<stop_line {
-                            deleted.push(line.to_owned());
-                            None
-                        }else {Some(line.to_owned())}
-                    }
-                ).collect()
-            };
-                if start_line+1 < self.content.len(){
-                    self.merge_lines(start_line, start_line+1);
-                }
-                self.state.selection = None;
-                self.state.curs_x = start_col; 
-                self.state.curs_y=start_line;  
-                deleted.push(last_elem);
-                let deleted = deleted.join("\n");
-                if add_final_endl {
-                    deleted +"\n"
-                }
-                else{
-                    deleted
-                }
-                
-                
-                
+            self.update_content();
+            deleted.push('\n');
+            deleted
+        } else {
+            let deleted = self.content.remove((start_line, start_col), (stop_line, stop_col));
+            self.update_content();
+            self.state.curs_x = start_col;
+            if start_line != stop_line {
+                self.state.curs_y = start_line;
             }
-    
+            deleted
+        }
     }
     pub fn merge_lines(&mut self, line1:usize, line2:usize){
         //remove highlight styiling
         self.unhighlight();
 
-        let to_merge  = self.content[line2].to_owned(); 
-        let merge_point = self.content[line1].len();
-        self.content.remove(line2);
-  
-        self.content[line1].insert_str(merge_point, &to_merge);
-       
+        let merge_point = self.content.line_len(line1);
+        if line2 == line1 + 1 {
+            self.content.join_line(line1);
+        }
+
         self.update_content();
-       
+
         self.adjust_cursor(line1, merge_point, false);
     }
     pub fn split_lines(&mut self, line1:usize, split_index:usize){
         self.unhighlight();
-        
-        let split = &self.content[line1][split_index..].to_owned();
-        if split_index == self.content[line1].len(){
-            self.content.insert(line1+1, String::new());
-            
-        }else if split_index == 0 {
-            self.content[line1].clear();
-            self.content.insert(line1+1, split.to_owned());
-        }
-        else{
-            self.content[line1] = self.content[line1][..split_index].to_string();
-            self.content.insert(line1+1, split.to_owned());
-        }
+
+        self.content.ensure_line(line1);
+        self.content.split_line(line1, split_index);
         self.update_content();
-        
+
     }
 
 
 
 }
 
+impl DiskStamp {
+    pub fn new(metadata: &fs::Metadata, bytes: &[u8]) -> DiskStamp {
+        DiskStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: content_hash(bytes),
+        }
+    }
+}
+
+// 64-bit FNV-1a, stable across runs so it can also be stored on disk.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 pub fn permission_string(mode: u32, is_dir: bool) -> String {
     let file_type = if is_dir { 'd' } else { '-' };
 
@@ -612,3 +1249,182 @@ pub fn permission_string(mode: u32, is_dir: bool) -> String {
         rwx(mode, 0o004, 0o002, 0o001), // Others
     )
 }
+
+// Short summary of what a group of edits changed, for the history browser.
+fn describe_ops(ops: &[EditOp]) -> String {
+    let mut added = String::new();
+    let mut removed = String::new();
+    let mut line = None;
+    for op in ops {
+        match op {
+            EditOp::InsertChar { line: l, ch, .. } => {
+                line.get_or_insert(*l);
+                added.push(*ch);
+            }
+            EditOp::InsetSelection { start, selection, .. } => {
+                line.get_or_insert(start.0);
+                added.push_str(selection);
+            }
+            EditOp::SplitLine { first_line, .. } => {
+                line.get_or_insert(*first_line);
+                added.push('\n');
+            }
+            // Backspacing removes text right to left
+            EditOp::DeleteChar { line: l, ch, .. } => {
+                line.get_or_insert(*l);
+                removed.insert_str(0, ch);
+            }
+            EditOp::DeleteSelection { start, stop, selection, .. } => {
+                line.get_or_insert(start.0.min(stop.0));
+                removed.push_str(selection);
+            }
+            EditOp::MergeLines { merged_line, .. } => {
+                line.get_or_insert(*merged_line);
+                removed.insert(0, '\n');
+            }
+            EditOp::Replace { line: l, removed: r, inserted: i, .. } => {
+                line.get_or_insert(*l);
+                removed.insert_str(0, r);
+                added.insert_str(0, i);
+            }
+        }
+    }
+    let shorten = |text: &str| {
+        let short: String = text.chars().take(24).collect();
+        if short.len() < text.len() { format!("{:?}...", short) } else { format!("{:?}", short) }
+    };
+    let mut parts = vec![format!("line {}", line.unwrap_or(0) + 1)];
+    if !removed.is_empty() {
+        parts.push(format!("-{}", shorten(&removed)));
+    }
+    if !added.is_empty() {
+        parts.push(format!("+{}", shorten(&added)));
+    }
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A document on an empty scratch file, one per test so they can run in parallel
+    fn scratch_doc(name: &str) -> Document {
+        let path = std::env::temp_dir().join(format!("tpad-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let mut doc = Document::new(&path.to_string_lossy().to_string()).unwrap();
+        let _ = fs::remove_file(&path);
+        doc.state.window_height = 20;
+        doc
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let text = "caf\u{e9} \u{1f600}";
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(encoding.decode(bytes).unwrap(), text);
+        }
+        assert_eq!(Encoding::Latin1.encode("caf\u{e9}").unwrap(), b"caf\xe9");
+        assert_eq!(Encoding::Latin1.decode(b"caf\xe9".to_vec()).unwrap(), "caf\u{e9}");
+        assert!(Encoding::Latin1.encode(text).is_err());
+        assert!(Encoding::Utf16Le.decode(vec![0x61]).is_err());
+    }
+
+    #[test]
+    fn typed_words_undo_one_at_a_time() {
+        let mut doc = scratch_doc("typing");
+        for c in "hello big world".chars() {
+            doc.type_char(c);
+        }
+        assert_eq!(doc.content.line(0), "hello big world");
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "hello big ");
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "hello ");
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "");
+        assert!(doc.undo().is_err());
+    }
+
+    #[test]
+    fn typing_over_selection_is_one_step() {
+        let mut doc = scratch_doc("replace");
+        for c in "abc".chars() {
+            doc.type_char(c);
+        }
+        doc.state.selection = Some(((0, 0), (0, 3)));
+        doc.type_char('x');
+        doc.type_char('y');
+        assert_eq!(doc.content.line(0), "xy");
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "x");
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "abc");
+    }
+
+    #[test]
+    fn undo_jump_switches_branches() {
+        let mut doc = scratch_doc("branches");
+        for c in "one two".chars() {
+            doc.type_char(c);
+        }
+        let two = doc.state.undo_stack.current_state();
+        doc.undo().unwrap();
+        // Typing after an undo starts a branch, "two" stays reachable
+        for c in "three".chars() {
+            doc.type_char(c);
+        }
+        let three = doc.state.undo_stack.current_state();
+        assert_eq!(doc.content.line(0), "one three");
+        doc.undo_jump(two).unwrap();
+        assert_eq!(doc.content.line(0), "one two");
+        doc.undo_jump(three).unwrap();
+        assert_eq!(doc.content.line(0), "one three");
+        doc.undo_jump(0).unwrap();
+        assert_eq!(doc.content.line(0), "");
+        assert!(doc.undo_jump(999).is_err());
+    }
+
+    // Backspace as the editor does it, recorded for undo
+    fn backspace(doc: &mut Document) {
+        let line = doc.state.scroll_offset + doc.state.curs_y;
+        let col = doc.state.curs_x;
+        let ch = doc.content.grapheme_at(line, col - 1).unwrap();
+        doc.state.undo_stack.push(EditOp::DeleteChar { line, col: col - 1, ch, applied: false });
+        doc.delete_char(line, col);
+    }
+
+    #[test]
+    fn redo_repeats_a_backspace() {
+        let mut doc = scratch_doc("backspace");
+        for c in "abcd".chars() {
+            doc.type_char(c);
+        }
+        backspace(&mut doc);
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "abcd");
+        doc.redo();
+        assert_eq!(doc.content.line(0), "abc");
+        assert_eq!(doc.state.curs_x, 3);
+
+        // Jumping across a backspace at column 1
+        let mut doc = scratch_doc("backspace-jump");
+        doc.type_char('a');
+        doc.type_char('b');
+        backspace(&mut doc);
+        let deleted = doc.state.undo_stack.current_state();
+        doc.undo_jump(0).unwrap();
+        assert_eq!(doc.content.line(0), "");
+        doc.undo_jump(deleted).unwrap();
+        assert_eq!(doc.content.line(0), "a");
+
+        let mut doc = scratch_doc("backspace-first");
+        doc.type_char('x');
+        backspace(&mut doc);
+        doc.undo().unwrap();
+        assert_eq!(doc.content.line(0), "x");
+        doc.redo();
+        assert_eq!(doc.content.line(0), "");
+        assert_eq!(doc.state.curs_x, 0);
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use ec4rs::property::{Charset, EndOfLine, FinalNewline, IndentSize, IndentStyle, TabWidth, TrimTrailingWs};
+
+use crate::{config::Config, data_models::*};
+
+// What the `.editorconfig` files above `path` say about it. Files that can't
+// be read or parsed are ignored, as are values tpad can't honour (the `cr`
+// line ending).
+pub fn resolve(path: &Path) -> EditorConfig {
+    let Ok(props) = ec4rs::properties_of(path) else {
+        return EditorConfig::default();
+    };
+    let tab_width = match props.get::<TabWidth>() {
+        Ok(TabWidth::Value(n)) if n > 0 => Some(n),
+        _ => None,
+    };
+    EditorConfig {
+        hard_tabs: match props.get::<IndentStyle>() {
+            Ok(IndentStyle::Tabs) => Some(true),
+            Ok(IndentStyle::Spaces) => Some(false),
+            _ => None,
+        },
+        indent_size: match props.get::<IndentSize>() {
+            Ok(IndentSize::Value(n)) if n > 0 => Some(n),
+            Ok(IndentSize::UseTabWidth) => tab_width,
+            _ => None,
+        },
+        tab_width,
+        line_ending: match props.get::<EndOfLine>() {
+            Ok(EndOfLine::Lf) => Some(LineEnding::Lf),
+            Ok(EndOfLine::CrLf) => Some(LineEnding::Crlf),
+            _ => None,
+        },
+        encoding: match props.get::<Charset>() {
+            Ok(Charset::Utf8 | Charset::Utf8Bom) => Some(Encoding::Utf8),
+            Ok(Charset::Latin1) => Some(Encoding::Latin1),
+            Ok(Charset::Utf16Le) => Some(Encoding::Utf16Le),
+            Ok(Charset::Utf16Be) => Some(Encoding::Utf16Be),
+            _ => None,
+        },
+        // UTF-16 files keep the byte order mark they were read with
+        bom: match props.get::<Charset>() {
+            Ok(Charset::Utf8 | Charset::Latin1) => Some(false),
+            Ok(Charset::Utf8Bom) => Some(true),
+            _ => None,
+        },
+        trim_trailing_whitespace: matches!(props.get::<TrimTrailingWs>(), Ok(TrimTrailingWs::Value(true))),
+        insert_final_newline: match props.get::<FinalNewline>() {
+            Ok(FinalNewline::Value(v)) => Some(v),
+            _ => None,
+        },
+    }
+}
+
+impl EditorConfig {
+    // Columns between tab stops, `indent_size` stands in when only it is set.
+    pub fn tab_width(&self, config: &Config) -> usize {
+        self.tab_width.or(self.indent_size).unwrap_or(config.tab_width).min(16)
+    }
+    // Whether Tab inserts a tab character, and the columns one indent step spans.
+    pub fn indent(&self, config: &Config) -> (bool, usize) {
+        let hard_tabs = self.hard_tabs.unwrap_or(!config.soft_tabs);
+        let width = self.indent_size.unwrap_or_else(|| self.tab_width(config)).min(16);
+        (hard_tabs, width)
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
+use ignore::WalkBuilder;
+
+use crate::data_models::*;
+
+// Indexing stops here, deeper trees need a narrower default directory.
+pub const MAX_INDEXED_FILES: usize = 20000;
+
+// Files below `root` as paths relative to it, skipping what .gitignore
+// excludes and hidden files.
+pub fn index_files(root: &Path) -> Vec<String> {
+    let mut files: Vec<String> = WalkBuilder::new(root)
+        .require_git(false)
+        .build()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .filter_map(|e| e.path().strip_prefix(root).ok().map(|p| p.to_string_lossy().to_string()))
+        .take(MAX_INDEXED_FILES)
+        .collect();
+    files.sort();
+    files
+}
+
+// Indices of the items matching `query`, best match first. An empty query
+// keeps every item in its original order.
+pub fn fuzzy_rank<S: AsRef<str>>(items: &[S], query: &str) -> Vec<usize> {
+    if query.is_empty() {
+        return (0..items.len()).collect();
+    }
+    let matcher = SkimMatcherV2::default().smart_case();
+    let mut scored: Vec<(i64, usize)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| matcher.fuzzy_match(item.as_ref(), query).map(|score| (score, i)))
+        .collect();
+    // Equal scores keep the shorter, then alphabetically first, item on top
+    scored.sort_by_key(|&(score, i)| (std::cmp::Reverse(score), items[i].as_ref().len(), i));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+
+// First lines of a file for the preview pane, empty for files that are not text.
+pub fn preview_lines(path: &Path, max_lines: usize) -> Vec<String> {
+    let Ok(file) = fs::File::open(path) else {
+        return Vec::new();
+    };
+    let mut lines = Vec::new();
+    for line in BufReader::new(file).split(b'\n').take(max_lines) {
+        match line {
+            Ok(bytes) if !bytes.contains(&0) => {
+                lines.push(String::from_utf8_lossy(&bytes).trim_end_matches('\r').replace('\t', "    "))
+            }
+            _ => return vec!["(binary file)".to_string()],
+        }
+    }
+    lines
+}
+
+impl FileFinder {
+    pub fn new(root: PathBuf) -> FileFinder {
+        let files = index_files(&root);
+        let matches = (0..files.len()).collect();
+        FileFinder {
+            root,
+            files,
+            query: String::new(),
+            matches,
+            selected: 0,
+        }
+    }
+    pub fn update(&mut self) {
+        self.matches = fuzzy_rank(&self.files, &self.query);
+        self.selected = 0;
+    }
+    // Full path of the highlighted file.
+    pub fn selected_path(&self) -> Option<PathBuf> {
+        let index = *self.matches.get(self.selected)?;
+        Some(self.root.join(&self.files[index]))
+    }
+}
@@ -0,0 +1,74 @@
+use std::{fs, path::Path};
+
+use ignore::WalkBuilder;
+use regex::Regex;
+
+use crate::{buffer::byte_to_col, data_models::*};
+
+// Stop collecting once this many lines matched, the results tab stays usable.
+pub const MAX_GREP_HITS: usize = 5000;
+
+// Title of the results tab, a new search replaces the previous results.
+pub const GREP_TAB: &str = "[grep]";
+
+// Every matching line below `dir`, skipping files ignored by .gitignore and
+// files that are not text. Returns the hits and whether the list was cut short.
+pub fn grep_dir(pattern: &Regex, dir: &Path) -> (Vec<GrepHit>, bool) {
+    let mut hits = Vec::new();
+    // .gitignore files count even when the directory is not a git checkout
+    for entry in WalkBuilder::new(dir).require_git(false).build().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let text = match fs::read(entry.path()) {
+            // A NUL byte is the usual sign of a binary file
+            Ok(bytes) if !bytes.contains(&0) => match String::from_utf8(bytes) {
+                Ok(text) => text,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        for (line, content) in text.lines().enumerate() {
+            let Some(m) = pattern.find_iter(content).find(|m| !m.is_empty()) else {
+                continue;
+            };
+            if hits.len() == MAX_GREP_HITS {
+                return (hits, true);
+            }
+            hits.push(GrepHit {
+                path: entry.path().to_string_lossy().to_string(),
+                line,
+                col: byte_to_col(content, m.start()),
+                text: content.trim_end_matches('\r').to_string(),
+            });
+        }
+    }
+    (hits, false)
+}
+
+// Text of the results tab: a summary, then one `path:line:col: text` line per
+// hit with paths shown relative to the searched directory.
+pub fn results_text(pattern: &str, dir: &Path, hits: &[GrepHit], truncated: bool) -> String {
+    let files = {
+        let mut paths: Vec<&str> = hits.iter().map(|h| h.path.as_str()).collect();
+        paths.dedup();
+        paths.len()
+    };
+    let mut text = format!(
+        "grep {} in {}: {} matches in {} files{}",
+        pattern,
+        dir.to_string_lossy(),
+        hits.len(),
+        files,
+        if truncated { " (stopped early, narrow the search)" } else { "" }
+    );
+    text.push('\n');
+    for hit in hits {
+        let shown = Path::new(&hit.path)
+            .strip_prefix(dir)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| hit.path.clone());
+        text.push_str(&format!("\n{}:{}:{}: {}", shown, hit.line + 1, hit.col + 1, hit.text));
+    }
+    text
+}
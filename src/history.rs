@@ -0,0 +1,62 @@
+use crate::data_models::*;
+
+// Older entries are dropped once a history holds this many lines.
+pub const MAX_HISTORY: usize = 200;
+
+impl HistoryKind {
+    // Lines starting with `/` are searches, everything else is a command.
+    pub fn of(line: &str) -> HistoryKind {
+        if line.trim_start().starts_with('/') {
+            HistoryKind::Search
+        } else {
+            HistoryKind::Command
+        }
+    }
+}
+
+impl CommandHistory {
+    pub fn entries(&self, kind: HistoryKind) -> &Vec<String> {
+        match kind {
+            HistoryKind::Command => &self.commands,
+            HistoryKind::Search => &self.searches,
+        }
+    }
+    // Records an executed line, a repeated line moves to the end instead of
+    // being stored twice.
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        let entries = match HistoryKind::of(line) {
+            HistoryKind::Command => &mut self.commands,
+            HistoryKind::Search => &mut self.searches,
+        };
+        entries.retain(|e| e != line);
+        entries.push(line.to_string());
+        if entries.len() > MAX_HISTORY {
+            entries.remove(0);
+        }
+    }
+    // The newest entry before `before` (or the newest overall) that starts with `prefix`.
+    pub fn older(&self, kind: HistoryKind, prefix: &str, before: Option<usize>) -> Option<usize> {
+        let entries = self.entries(kind);
+        let end = before.unwrap_or(entries.len()).min(entries.len());
+        entries[..end].iter().rposition(|e| e.starts_with(prefix))
+    }
+    // The oldest entry after `after` that starts with `prefix`.
+    pub fn newer(&self, kind: HistoryKind, prefix: &str, after: usize) -> Option<usize> {
+        self.entries(kind)
+            .iter()
+            .enumerate()
+            .skip(after + 1)
+            .find(|(_, e)| e.starts_with(prefix))
+            .map(|(i, _)| i)
+    }
+    // Ctrl+R lookup: the newest entry before `before` containing `query`.
+    pub fn find_back(&self, kind: HistoryKind, query: &str, before: Option<usize>) -> Option<usize> {
+        let entries = self.entries(kind);
+        let end = before.unwrap_or(entries.len()).min(entries.len());
+        entries[..end].iter().rposition(|e| e.contains(query))
+    }
+}
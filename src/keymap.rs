@@ -0,0 +1,264 @@
+use std::{fs, path::PathBuf};
+
+use crossterm::event::{KeyCode, KeyModifiers};
+use dirs::config_dir;
+
+use crate::{
+    actions::{self, Action, ACTIONS},
+    data_models::*,
+};
+
+// Binding a sequence to this removes it, e.g. to free a default key.
+pub const UNBIND: &str = "none";
+
+pub enum KeyLookup {
+    Action(&'static Action),
+    // The keys so far start a longer sequence
+    Pending,
+    Unbound,
+}
+
+pub fn get_keybindings_file_path() -> PathBuf {
+    let base_dir = config_dir().unwrap_or_else(|| PathBuf::from("."));
+    base_dir.join("tpad").join("keybindings.toml")
+}
+
+// Shifted letters arrive as uppercase with SHIFT, other shifted characters
+// carry the shift in the character itself. Both are stored one way so a
+// binding matches however the terminal reports it.
+pub fn chord(code: KeyCode, modifiers: KeyModifiers) -> KeyChord {
+    match code {
+        KeyCode::Char(c) if c.is_ascii_uppercase() => (KeyCode::Char(c.to_ascii_lowercase()), modifiers | KeyModifiers::SHIFT),
+        KeyCode::Char(c) if !c.is_ascii_alphabetic() => (code, modifiers - KeyModifiers::SHIFT),
+        _ => (code, modifiers),
+    }
+}
+
+// A chord written as in the keybindings file, e.g. "ctrl+s" or "alt+shift+left".
+pub fn parse_chord(text: &str) -> Result<KeyChord, String> {
+    let (mods, key) = if text == "+" {
+        ("", "+")
+    } else if let Some(mods) = text.strip_suffix("++") {
+        (mods, "+")
+    } else {
+        text.rsplit_once('+').unwrap_or(("", text))
+    };
+    let mut modifiers = KeyModifiers::NONE;
+    for m in mods.split('+').filter(|m| !m.is_empty()) {
+        modifiers |= match m.to_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return Err(format!("unknown modifier `{}`", m)),
+        };
+    }
+    let lower = key.to_lowercase();
+    let code = match lower.as_str() {
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "space" => KeyCode::Char(' '),
+        _ if lower.starts_with('f') && lower.len() > 1 => match lower[1..].parse::<u8>() {
+            Ok(n) if (1..=12).contains(&n) => KeyCode::F(n),
+            _ => return Err(format!("unknown key `{}`", key)),
+        },
+        // Letters are case insensitive, shift is spelled out
+        _ if lower.chars().count() == 1 => KeyCode::Char(lower.chars().next().unwrap_or(' ')),
+        _ => return Err(format!("unknown key `{}`", key)),
+    };
+    Ok(chord(code, modifiers))
+}
+
+// Space separated chords pressed one after the other, e.g. "ctrl+k ctrl+w".
+pub fn parse_sequence(text: &str) -> Result<Vec<KeyChord>, String> {
+    let keys = text.split_whitespace().map(parse_chord).collect::<Result<Vec<KeyChord>, String>>()?;
+    if keys.is_empty() {
+        return Err("no keys given".to_string());
+    }
+    Ok(keys)
+}
+
+pub fn sequence_label(keys: &[KeyChord]) -> String {
+    keys.iter()
+        .map(|(code, modifiers)| actions::key_label(*code, *modifiers))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Written when there is no keybindings file yet, every default commented out.
+fn default_file() -> String {
+    let mut text = String::from(
+        "# tpad keybindings, loaded at startup and when this file is saved.\n\
+         # Map a key, or space separated keys pressed in turn, to an action:\n\
+         #   \"ctrl+k ctrl+w\" = \"q\"\n\
+         # Modifiers are ctrl, alt and shift. Bind a key to \"none\" to free it.\n\
+         # The palette (Alt+X) lists every action.\n\
+         #\n\
+         # Defaults:\n",
+    );
+    for action in ACTIONS {
+        if let Some((code, modifiers)) = action.key {
+            let keys = actions::key_label(code, modifiers).to_lowercase();
+            text.push_str(&format!("# \"{}\" = \"{}\"\n", keys, action.name));
+        }
+    }
+    text
+}
+
+impl Keymap {
+    // Every action on its default key.
+    pub fn defaults() -> Keymap {
+        let bindings = ACTIONS
+            .iter()
+            .filter_map(|a| a.key.map(|(code, modifiers)| (vec![chord(code, modifiers)], a)))
+            .collect();
+        Keymap { bindings }
+    }
+    // The defaults with the keybindings file applied on top, and what was
+    // wrong in it. A missing file is created with the defaults commented out.
+    pub fn load() -> (Keymap, Vec<String>) {
+        let mut keymap = Keymap::defaults();
+        let path = get_keybindings_file_path();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let errors = keymap.apply(&content);
+                (keymap, errors)
+            }
+            Err(_) => {
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                let _ = fs::write(&path, default_file());
+                (keymap, Vec::new())
+            }
+        }
+    }
+    // Applies the bindings in `content`, invalid ones are skipped and reported.
+    pub fn apply(&mut self, content: &str) -> Vec<String> {
+        let table: toml::Table = match toml::from_str(content) {
+            Ok(table) => table,
+            Err(e) => return vec![e.message().to_string()],
+        };
+        let mut errors = Vec::new();
+        let mut seen: Vec<Vec<KeyChord>> = Vec::new();
+        for (text, value) in &table {
+            let Some(name) = value.as_str() else {
+                errors.push(format!("\"{}\": the action must be a string", text));
+                continue;
+            };
+            let keys = match parse_sequence(text) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    errors.push(format!("\"{}\": {}", text, e));
+                    continue;
+                }
+            };
+            // Keys of a sequence are held back until it is complete, starting
+            // one with a plain key would swallow that key while typing
+            if keys.len() > 1 && !keys[0].1.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                errors.push(format!("\"{}\": a sequence has to start with a ctrl or alt key", text));
+                continue;
+            }
+            if seen.contains(&keys) {
+                errors.push(format!("\"{}\": {} is bound more than once", text, sequence_label(&keys)));
+                continue;
+            }
+            seen.push(keys.clone());
+            self.bindings.retain(|(k, _)| *k != keys);
+            if name == UNBIND {
+                continue;
+            }
+            match actions::by_name(name) {
+                Some(action) => self.bindings.push((keys, action)),
+                None => errors.push(format!("\"{}\": unknown action `{}`", text, name)),
+            }
+        }
+        // A key that runs an action can't also start a longer sequence
+        let mut i = 0;
+        while i < self.bindings.len() {
+            let keys = &self.bindings[i].0;
+            let shadow = self.bindings.iter().find(|(k, _)| k.len() < keys.len() && keys.starts_with(k));
+            match shadow {
+                Some((prefix, action)) => {
+                    errors.push(format!(
+                        "{} never runs {}, {} already runs {}",
+                        sequence_label(keys),
+                        self.bindings[i].1.name,
+                        sequence_label(prefix),
+                        action.name
+                    ));
+                    self.bindings.remove(i);
+                }
+                None => i += 1,
+            }
+        }
+        errors
+    }
+    pub fn lookup(&self, keys: &[KeyChord]) -> KeyLookup {
+        if let Some((_, action)) = self.bindings.iter().find(|(k, _)| k == keys) {
+            return KeyLookup::Action(action);
+        }
+        if self.bindings.iter().any(|(k, _)| k.starts_with(keys)) {
+            KeyLookup::Pending
+        } else {
+            KeyLookup::Unbound
+        }
+    }
+    // Every key sequence running `name`, as shown to the user.
+    pub fn keys_for(&self, name: &str) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|(_, action)| action.name == name)
+            .map(|(keys, _)| sequence_label(keys))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chords_with_modifiers() {
+        assert_eq!(parse_chord("ctrl+s"), Ok((KeyCode::Char('s'), KeyModifiers::CONTROL)));
+        assert_eq!(parse_chord("Alt+Shift+Left"), Ok((KeyCode::Left, KeyModifiers::ALT | KeyModifiers::SHIFT)));
+        assert_eq!(parse_chord("control+F5"), Ok((KeyCode::F(5), KeyModifiers::CONTROL)));
+        assert_eq!(parse_chord("space"), Ok((KeyCode::Char(' '), KeyModifiers::NONE)));
+        // Letters are case insensitive
+        assert_eq!(parse_chord("ctrl+S"), parse_chord("ctrl+s"));
+    }
+
+    #[test]
+    fn plus_as_a_key() {
+        assert_eq!(parse_chord("+"), Ok((KeyCode::Char('+'), KeyModifiers::NONE)));
+        assert_eq!(parse_chord("ctrl++"), Ok((KeyCode::Char('+'), KeyModifiers::CONTROL)));
+    }
+
+    #[test]
+    fn unknown_chords() {
+        assert!(parse_chord("hyper+x").is_err());
+        assert!(parse_chord("f13").is_err());
+        assert!(parse_chord("ctrl+nope").is_err());
+    }
+
+    #[test]
+    fn sequences_start_with_a_modifier() {
+        let mut keymap = Keymap::defaults();
+        let errors = keymap.apply("\"g g\" = \"q\"\n\"shift+g x\" = \"q\"\n\"ctrl+g g\" = \"q\"\n");
+        assert_eq!(errors.len(), 2);
+        // A plain `g` still types
+        assert!(matches!(keymap.lookup(&[chord(KeyCode::Char('g'), KeyModifiers::NONE)]), KeyLookup::Unbound));
+        assert!(matches!(keymap.lookup(&[chord(KeyCode::Char('g'), KeyModifiers::CONTROL)]), KeyLookup::Pending));
+    }
+}
@@ -1,7 +1,23 @@
+pub mod actions;
 pub mod app;
+pub mod buffer;
+pub mod complete;
+pub mod config;
 pub mod data_models;
+pub mod diff;
 pub mod doc;
+pub mod editorconfig;
+pub mod finder;
+pub mod grep;
+pub mod history;
+pub mod keymap;
+pub mod panes;
+pub mod parse_tree;
+pub mod save;
+pub mod search;
 pub mod session;
+pub mod swap;
+pub mod syntax;
 
 pub mod ui;
 pub mod theme;
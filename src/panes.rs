@@ -0,0 +1,336 @@
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+
+use crate::data_models::*;
+
+// Smallest share of a split resizing leaves either side, in percent
+const MIN_PERCENT: i16 = 10;
+// Percent one resize moves the edge of a pane by
+pub const RESIZE_STEP: i16 = 5;
+// Smallest pane a split may leave, border included
+const MIN_HEIGHT: u16 = 4;
+const MIN_WIDTH: u16 = 16;
+
+impl PaneLayout {
+    // Screen area of every pane when the layout fills `area`.
+    pub fn areas(&self, area: Rect, out: &mut Vec<(usize, Rect)>) {
+        match self {
+            PaneLayout::Pane(index) => out.push((*index, area)),
+            PaneLayout::Split { direction, percent, first, second } => {
+                let chunks = Layout::default()
+                    .direction(*direction)
+                    .constraints([Constraint::Percentage(*percent), Constraint::Percentage(100 - *percent)])
+                    .split(area);
+                first.areas(chunks[0], out);
+                second.areas(chunks[1], out);
+            }
+        }
+    }
+    fn contains(&self, pane: usize) -> bool {
+        match self {
+            PaneLayout::Pane(index) => *index == pane,
+            PaneLayout::Split { first, second, .. } => first.contains(pane) || second.contains(pane),
+        }
+    }
+    // Splits `pane` in two, `new` goes right of or below it.
+    pub fn split(&mut self, pane: usize, new: usize, direction: Direction) {
+        match self {
+            PaneLayout::Pane(index) if *index == pane => {
+                *self = PaneLayout::Split {
+                    direction,
+                    percent: 50,
+                    first: Box::new(PaneLayout::Pane(pane)),
+                    second: Box::new(PaneLayout::Pane(new)),
+                };
+            }
+            PaneLayout::Pane(_) => {}
+            PaneLayout::Split { first, second, .. } => {
+                first.split(pane, new, direction);
+                second.split(pane, new, direction);
+            }
+        }
+    }
+    // Takes `pane` out, the other side of its split gets the space. Indexes
+    // after it move down one, as they do in `App::panes`.
+    pub fn remove(&mut self, pane: usize) {
+        self.unlink(pane);
+        self.renumber(pane);
+    }
+    fn unlink(&mut self, pane: usize) {
+        let PaneLayout::Split { first, second, .. } = self else {
+            return;
+        };
+        let rest = if matches!(**first, PaneLayout::Pane(index) if index == pane) {
+            std::mem::replace(&mut **second, PaneLayout::Pane(pane))
+        } else if matches!(**second, PaneLayout::Pane(index) if index == pane) {
+            std::mem::replace(&mut **first, PaneLayout::Pane(pane))
+        } else {
+            first.unlink(pane);
+            second.unlink(pane);
+            return;
+        };
+        *self = rest;
+    }
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            PaneLayout::Pane(index) if *index > removed => *index -= 1,
+            PaneLayout::Pane(_) => {}
+            PaneLayout::Split { first, second, .. } => {
+                first.renumber(removed);
+                second.renumber(removed);
+            }
+        }
+    }
+    // Grows `pane` by `delta` percent of the innermost split holding it,
+    // false when it is not split.
+    pub fn resize(&mut self, pane: usize, delta: i16) -> bool {
+        let PaneLayout::Split { percent, first, second, .. } = self else {
+            return false;
+        };
+        if first.resize(pane, delta) || second.resize(pane, delta) {
+            return true;
+        }
+        let delta = if first.contains(pane) {
+            delta
+        } else if second.contains(pane) {
+            -delta
+        } else {
+            return false;
+        };
+        *percent = (*percent as i16 + delta).clamp(MIN_PERCENT, 100 - MIN_PERCENT) as u16;
+        true
+    }
+}
+
+// Moves a line number along with an edit starting at line `from` that
+// added `delta` lines, lines it removed collapse onto `from`.
+fn shift_line(line: usize, from: usize, delta: isize) -> usize {
+    if line > from {
+        line.saturating_add_signed(delta).max(from)
+    } else {
+        line
+    }
+}
+
+impl View {
+    // Follows an edit made through another view of the same document.
+    fn shift(&mut self, from: usize, delta: isize) {
+        let line = shift_line(self.scroll_offset + self.curs_y, from, delta);
+        self.scroll_offset = shift_line(self.scroll_offset, from, delta).min(line);
+        self.curs_y = line - self.scroll_offset;
+        self.selection = self
+            .selection
+            .map(|(a, b)| ((shift_line(a.0, from, delta), a.1), (shift_line(b.0, from, delta), b.1)));
+    }
+    // Keeps the cursor inside the document after it got shorter.
+    fn clamp(&mut self, doc: &Document) {
+        let last = doc.content.len_lines().saturating_sub(1);
+        let line = (self.scroll_offset + self.curs_y).min(last);
+        self.scroll_offset = self.scroll_offset.min(line);
+        self.curs_y = line - self.scroll_offset;
+        self.curs_x = self.curs_x.min(doc.content.line_len(line));
+        if self.selection.is_some_and(|(a, b)| a.0 > last || b.0 > last) {
+            self.selection = None;
+        }
+    }
+}
+
+impl App {
+    // The focused pane's view is kept in its document, this copies it into
+    // the pane before another pane takes the focus.
+    fn store_focused_view(&mut self) {
+        let Some(doc) = self.documents.get(self.active) else {
+            return;
+        };
+        let pane = &mut self.panes[self.focused_pane];
+        pane.doc = self.active;
+        pane.view = doc.state.view();
+        pane.lines = doc.content.len_lines();
+    }
+    fn load_pane(&mut self, index: usize) {
+        self.focused_pane = index;
+        let pane = &mut self.panes[index];
+        if let Some(doc) = self.documents.get_mut(pane.doc) {
+            pane.view.clamp(doc);
+            doc.state.set_view(pane.view);
+            self.active = pane.doc;
+        }
+    }
+    pub fn focus_pane(&mut self, index: usize) {
+        if index == self.focused_pane || index >= self.panes.len() {
+            return;
+        }
+        self.store_focused_view();
+        self.load_pane(index);
+    }
+    // Splits the focused pane, the new pane shows the same view and gets the focus.
+    pub fn split_pane(&mut self, direction: Direction) {
+        if self.documents.is_empty() {
+            return;
+        }
+        if let Some(area) = self.pane_areas.get(self.focused_pane) {
+            let too_small = match direction {
+                Direction::Vertical => area.height / 2 < MIN_HEIGHT,
+                Direction::Horizontal => area.width / 2 < MIN_WIDTH,
+            };
+            if too_small {
+                self.show_popup("The pane is too small to split".to_string(), PopupTypes::InfoPopup);
+                return;
+            }
+        }
+        self.store_focused_view();
+        self.panes.push(self.panes[self.focused_pane].clone());
+        let new = self.panes.len() - 1;
+        self.pane_layout.split(self.focused_pane, new, direction);
+        self.focused_pane = new;
+    }
+    // Closes the focused pane, its document stays open.
+    pub fn close_pane(&mut self) {
+        if self.panes.len() == 1 {
+            self.show_popup("This is the only pane, use q to close the tab".to_string(), PopupTypes::InfoPopup);
+            return;
+        }
+        let closed = self.focused_pane;
+        self.panes.remove(closed);
+        self.pane_layout.remove(closed);
+        self.load_pane(closed.min(self.panes.len() - 1));
+    }
+    pub fn next_pane(&mut self) {
+        self.focus_pane((self.focused_pane + 1) % self.panes.len());
+    }
+    // Focuses the nearest pane on the given side of the focused one.
+    pub fn focus_toward(&mut self, direction: CursorDirection) {
+        let Some(from) = self.pane_areas.get(self.focused_pane).copied() else {
+            return;
+        };
+        let center = |r: &Rect| (r.x as i32 + r.width as i32 / 2, r.y as i32 + r.height as i32 / 2);
+        let (x, y) = center(&from);
+        let nearest = self
+            .pane_areas
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| match direction {
+                CursorDirection::Left => r.right() <= from.left(),
+                CursorDirection::Right => r.left() >= from.right(),
+                CursorDirection::Up => r.bottom() <= from.top(),
+                CursorDirection::Down => r.top() >= from.bottom(),
+            })
+            .min_by_key(|(_, r)| {
+                let (cx, cy) = center(r);
+                (cx - x).abs() + (cy - y).abs()
+            })
+            .map(|(i, _)| i);
+        if let Some(index) = nearest {
+            self.focus_pane(index);
+        }
+    }
+    pub fn resize_pane(&mut self, delta: i16) {
+        self.pane_layout.resize(self.focused_pane, delta);
+    }
+    // Panes showing the document at `index` move to the active one after it
+    // was closed, later documents moved down an index.
+    pub fn document_closed(&mut self, index: usize) {
+        if self.documents.is_empty() {
+            self.panes = vec![Pane::default()];
+            self.pane_layout = PaneLayout::Pane(0);
+            self.focused_pane = 0;
+            return;
+        }
+        for pane in &mut self.panes {
+            if pane.doc == index {
+                pane.doc = self.active;
+                pane.view = self.documents[self.active].state.view();
+            } else if pane.doc > index {
+                pane.doc -= 1;
+            }
+        }
+    }
+    // Runs before each frame: the focused pane follows tab switches, and the
+    // other views of the active document move along with lines added or
+    // removed above them.
+    pub fn sync_panes(&mut self) {
+        let Some(doc) = self.documents.get(self.active) else {
+            return;
+        };
+        let lines = doc.content.len_lines();
+        for (index, pane) in self.panes.iter_mut().enumerate() {
+            if index == self.focused_pane {
+                pane.doc = self.active;
+                pane.lines = lines;
+                continue;
+            }
+            let Some(shown) = self.documents.get(pane.doc) else {
+                pane.doc = self.active;
+                continue;
+            };
+            if pane.doc == self.active
+                && pane.lines != lines
+                && let Some(from) = doc.content.edited_from()
+            {
+                pane.view.shift(from, lines as isize - pane.lines as isize);
+            }
+            pane.lines = shown.content.len_lines();
+            pane.view.clamp(shown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn areas(layout: &PaneLayout) -> Vec<(usize, Rect)> {
+        let mut out = Vec::new();
+        layout.areas(Rect::new(0, 0, 100, 40), &mut out);
+        out
+    }
+
+    #[test]
+    fn split_places_the_new_pane_after() {
+        let mut layout = PaneLayout::Pane(0);
+        layout.split(0, 1, Direction::Horizontal);
+        layout.split(1, 2, Direction::Vertical);
+        assert_eq!(
+            areas(&layout),
+            [(0, Rect::new(0, 0, 50, 40)), (1, Rect::new(50, 0, 50, 20)), (2, Rect::new(50, 20, 50, 20))]
+        );
+    }
+
+    #[test]
+    fn remove_gives_the_space_to_the_sibling() {
+        let mut layout = PaneLayout::Pane(0);
+        layout.split(0, 1, Direction::Horizontal);
+        layout.split(1, 2, Direction::Vertical);
+        layout.remove(1);
+        // Pane 2 took the place of 1 and moved down an index
+        assert_eq!(areas(&layout), [(0, Rect::new(0, 0, 50, 40)), (1, Rect::new(50, 0, 50, 40))]);
+        layout.remove(0);
+        assert!(matches!(layout, PaneLayout::Pane(0)));
+    }
+
+    #[test]
+    fn resize_moves_the_innermost_split() {
+        let mut layout = PaneLayout::Pane(0);
+        assert!(!layout.resize(0, RESIZE_STEP));
+        layout.split(0, 1, Direction::Horizontal);
+        layout.split(1, 2, Direction::Vertical);
+        assert!(layout.resize(2, RESIZE_STEP));
+        assert_eq!(areas(&layout)[1..], [(1, Rect::new(50, 0, 50, 18)), (2, Rect::new(50, 18, 50, 22))]);
+        // Pane 0 is on the first side of the outer split
+        assert!(layout.resize(0, 100));
+        assert_eq!(areas(&layout)[0], (0, Rect::new(0, 0, 90, 40)));
+    }
+
+    #[test]
+    fn views_follow_edits_above_them() {
+        let mut view = View { curs_y: 2, scroll_offset: 10, selection: Some(((11, 0), (13, 2))), ..View::default() };
+        view.shift(5, 3);
+        assert_eq!((view.scroll_offset, view.curs_y), (13, 2));
+        assert_eq!(view.selection, Some(((14, 0), (16, 2))));
+        // Edits below the view leave it alone
+        view.shift(20, -2);
+        assert_eq!((view.scroll_offset, view.curs_y), (13, 2));
+        // Removed lines holding the cursor collapse onto the edit
+        view.shift(12, -10);
+        assert_eq!(view.scroll_offset + view.curs_y, 12);
+    }
+}
@@ -0,0 +1,283 @@
+use std::path::Path;
+
+use tree_sitter::{Language, Node, Parser};
+
+use crate::{buffer::TextBuffer, data_models::*};
+
+// A language tpad has a tree-sitter grammar for.
+pub struct Grammar {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    language: fn() -> Language,
+    // Label of a node that belongs in the outline, None for every other node
+    outline: fn(Node, &TextBuffer) -> Option<String>,
+}
+
+pub const GRAMMARS: &[Grammar] = &[
+    Grammar {
+        name: "Rust",
+        extensions: &["rs"],
+        language: || tree_sitter_rust::LANGUAGE.into(),
+        outline: rust_outline,
+    },
+    Grammar {
+        name: "Python",
+        extensions: &["py", "pyw", "pyi"],
+        language: || tree_sitter_python::LANGUAGE.into(),
+        outline: python_outline,
+    },
+    Grammar {
+        name: "JSON",
+        extensions: &["json"],
+        language: || tree_sitter_json::LANGUAGE.into(),
+        outline: json_outline,
+    },
+    Grammar {
+        name: "Markdown",
+        extensions: &["md", "markdown"],
+        language: || tree_sitter_md::LANGUAGE.into(),
+        outline: markdown_outline,
+    },
+    Grammar {
+        name: "TOML",
+        extensions: &["toml"],
+        language: || tree_sitter_toml_ng::LANGUAGE.into(),
+        outline: toml_outline,
+    },
+];
+
+pub fn detect(path: &Path) -> Option<&'static Grammar> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    GRAMMARS.iter().find(|g| g.extensions.contains(&extension.as_str()))
+}
+
+fn text(node: Node, content: &TextBuffer) -> String {
+    content.byte_slice(node.start_byte(), node.end_byte())
+}
+
+fn field_text(node: Node, field: &str, content: &TextBuffer) -> String {
+    node.child_by_field_name(field).map(|n| text(n, content)).unwrap_or_default()
+}
+
+fn rust_outline(node: Node, content: &TextBuffer) -> Option<String> {
+    let keyword = match node.kind() {
+        "function_item" | "function_signature_item" => "fn",
+        "struct_item" => "struct",
+        "enum_item" => "enum",
+        "union_item" => "union",
+        "trait_item" => "trait",
+        "mod_item" => "mod",
+        "macro_definition" => "macro_rules!",
+        // An impl is named after its type and the trait it implements
+        "impl_item" => {
+            let ty = field_text(node, "type", content);
+            return Some(match node.child_by_field_name("trait") {
+                Some(t) => format!("impl {} for {}", text(t, content), ty),
+                None => format!("impl {}", ty),
+            });
+        }
+        _ => return None,
+    };
+    Some(format!("{} {}", keyword, field_text(node, "name", content)))
+}
+
+fn python_outline(node: Node, content: &TextBuffer) -> Option<String> {
+    let keyword = match node.kind() {
+        "function_definition" => "def",
+        "class_definition" => "class",
+        _ => return None,
+    };
+    Some(format!("{} {}", keyword, field_text(node, "name", content)))
+}
+
+// Keys holding an object or array, plain values would drown them out
+fn json_outline(node: Node, content: &TextBuffer) -> Option<String> {
+    if node.kind() != "pair" {
+        return None;
+    }
+    let value = node.child_by_field_name("value")?;
+    if !matches!(value.kind(), "object" | "array") {
+        return None;
+    }
+    Some(field_text(node, "key", content))
+}
+
+fn markdown_outline(node: Node, content: &TextBuffer) -> Option<String> {
+    match node.kind() {
+        "atx_heading" => Some(text(node, content).trim().to_string()),
+        // The underline says the level, the first line is the heading
+        "setext_heading" => {
+            let level = if text(node, content).trim_end().ends_with('=') { "#" } else { "##" };
+            let title = field_text(node, "heading_content", content);
+            Some(format!("{} {}", level, title.lines().next().unwrap_or("").trim()))
+        }
+        _ => None,
+    }
+}
+
+fn toml_outline(node: Node, content: &TextBuffer) -> Option<String> {
+    let (open, close) = match node.kind() {
+        "table" => ("[", "]"),
+        "table_array_element" => ("[[", "]]"),
+        _ => return None,
+    };
+    let key = node.named_child(0).map(|k| text(k, content)).unwrap_or_default();
+    Some(format!("{}{}{}", open, key, close))
+}
+
+impl ParseTree {
+    pub fn new(grammar: &'static Grammar) -> Option<ParseTree> {
+        let mut parser = Parser::new();
+        parser.set_language(&(grammar.language)()).ok()?;
+        Some(ParseTree { grammar, parser, tree: None })
+    }
+}
+
+impl Document {
+    // Brings the parse tree up to date with the edits made since the last
+    // call, reusing what they left untouched.
+    pub fn refresh_tree(&mut self) {
+        let edits = self.content.take_edits();
+        let Some(parse) = &mut self.tree else {
+            return;
+        };
+        let old_tree = match (edits, &mut parse.tree) {
+            (Some(edits), Some(tree)) => {
+                if edits.is_empty() {
+                    return;
+                }
+                for edit in &edits {
+                    tree.edit(edit);
+                }
+                Some(&*tree)
+            }
+            _ => None,
+        };
+        let content = &self.content;
+        let tree = parse.parser.parse_with_options(&mut |byte, _| content.chunk_at_byte(byte), old_tree, None);
+        parse.tree = tree;
+    }
+
+    // Root of the up to date parse tree, None for documents without a grammar.
+    pub fn root_node(&mut self) -> Option<Node<'_>> {
+        self.refresh_tree();
+        self.tree.as_ref()?.tree.as_ref().map(|t| t.root_node())
+    }
+
+    // Byte range of the selection, or an empty range at the cursor.
+    pub fn selected_bytes(&self) -> (usize, usize) {
+        match self.state.selection {
+            Some((a, b)) => {
+                let (a, b) = (self.content.byte_index(a.0, a.1), self.content.byte_index(b.0, b.1));
+                (a.min(b), a.max(b))
+            }
+            None => {
+                let cursor = self.content.byte_index(self.state.scroll_offset + self.state.curs_y, self.state.curs_x);
+                (cursor, cursor)
+            }
+        }
+    }
+
+    // Byte range of the smallest named node that holds the selection and is
+    // larger than it, so calling this again keeps growing.
+    pub fn enclosing_node(&mut self) -> Option<(usize, usize)> {
+        let (start, end) = self.selected_bytes();
+        let mut node = self.root_node()?.named_descendant_for_byte_range(start, end)?;
+        while (node.start_byte(), node.end_byte()) == (start, end) {
+            node = node.parent()?;
+        }
+        Some((node.start_byte(), node.end_byte()))
+    }
+
+    // Start of the closest named node around the cursor that begins before it.
+    pub fn parent_node(&mut self) -> Option<usize> {
+        let (cursor, _) = self.selected_bytes();
+        let mut node = self.root_node()?.named_descendant_for_byte_range(cursor, cursor)?;
+        while node.start_byte() >= cursor {
+            node = node.parent()?;
+        }
+        Some(node.start_byte())
+    }
+
+    // Start of the node after the one at the cursor, climbing out of nodes
+    // that have nothing after them.
+    pub fn next_sibling_node(&mut self) -> Option<usize> {
+        let (cursor, _) = self.selected_bytes();
+        let root = self.root_node()?;
+        let mut node = root.named_descendant_for_byte_range(cursor, cursor)?;
+        // The largest node starting here, so its children are skipped over
+        while let Some(parent) = node.parent() {
+            if parent.start_byte() != node.start_byte() || parent == root {
+                break;
+            }
+            node = parent;
+        }
+        loop {
+            if let Some(next) = node.next_named_sibling() {
+                return Some(next.start_byte());
+            }
+            node = node.parent()?;
+        }
+    }
+
+    // Functions, types, headings and tables of the document in order, nested
+    // ones indented under the item they belong to.
+    pub fn outline(&mut self) -> Vec<OutlineItem> {
+        self.refresh_tree();
+        let Some(ParseTree { grammar, tree: Some(tree), .. }) = &self.tree else {
+            return Vec::new();
+        };
+        let mut items = Vec::new();
+        let mut cursor = tree.walk();
+        let mut depth = 0;
+        // Outline depth of each node on the path from the root to the cursor
+        let mut depths = Vec::new();
+        loop {
+            let node = cursor.node();
+            let mut child_depth = depth;
+            if let Some(label) = (grammar.outline)(node, &self.content) {
+                // Names split over lines are shown on one
+                let label = label.split_whitespace().collect::<Vec<&str>>().join(" ");
+                let (line, col) = self.content.position_at_byte(node.start_byte());
+                items.push(OutlineItem { label, line, col, depth });
+                child_depth += 1;
+            }
+            if cursor.goto_first_child() {
+                depths.push(depth);
+                depth = child_depth;
+                continue;
+            }
+            loop {
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+                if !cursor.goto_parent() {
+                    return items;
+                }
+                depth = depths.pop().unwrap_or(0);
+            }
+        }
+    }
+
+    // Selects a byte range, the cursor goes to its end.
+    pub fn select_bytes(&mut self, start: usize, end: usize) {
+        let start = self.content.position_at_byte(start);
+        let end = self.content.position_at_byte(end);
+        self.state.selection = Some((start, end));
+        self.adjust_cursor(end.0, end.1, false);
+    }
+
+    pub fn goto_byte(&mut self, byte: usize) {
+        let (line, col) = self.content.position_at_byte(byte);
+        self.state.selection = None;
+        self.adjust_cursor(line, col, false);
+    }
+}
+
+pub fn outline_text(items: &[OutlineItem]) -> String {
+    items
+        .iter()
+        .map(|item| format!("{}{}  {}", "  ".repeat(item.depth), item.label, item.line + 1))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
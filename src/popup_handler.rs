@@ -2,7 +2,7 @@ use std::{fs, io::Write, path::PathBuf};
 
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
-use crate::{data_models::*, theme::get_theme_file_path};
+use crate::{app::{external_change_prompt, swap_prompt, undo_tree_text}, data_models::*, diff, swap, theme::get_theme_file_path};
 use crate::theme::*;
 
 
@@ -27,14 +27,20 @@ impl App {
     pub fn handle_popup(&mut self, mut popup: Popup, key_event: KeyEvent) -> Option<Popup> {
         match popup.kind {
             PopupTypes::ErrorPopup => {
-                // Dismiss the popup by returning None.
-                None
+                // Dismiss the popup, startup may still have swap files to offer.
+                self.show_next_swap();
+                self.popup.take()
             }
             PopupTypes::SaveOnClosePopup => {
                 match (key_event.code, key_event.modifiers) {
                     (KeyCode::Char('y'), KeyModifiers::NONE) => {
-                        if let Some(active_doc) = self.documents.get_mut(self.active) {
-                            active_doc.save_file().unwrap();
+                        if !self.documents.is_empty() {
+                            match self.save_active() {
+                                Ok(true) => {}
+                                // Asked about a change on disk, don't quit yet
+                                Ok(false) => return self.popup.take(),
+                                Err(e) => return Some(Popup::new(e.to_string(), PopupTypes::ErrorPopup)),
+                            }
                         }
                         self.exit().ok();
                         // Dismiss the popup by returning None.
@@ -42,6 +48,10 @@ impl App {
                     }
                     (KeyCode::Char('n'), KeyModifiers::NONE) => {
                         self.exit().ok();
+                        // The user chose to drop the active file's changes
+                        if let Some(doc) = self.documents.get(self.active) {
+                            swap::remove_swap(&doc.file_path);
+                        }
                         // Dismiss the popup by returning None.
                         None
                     }
@@ -122,7 +132,258 @@ impl App {
 
             }
             PopupTypes::InfoPopup => {
-                None  
+                self.show_next_swap();
+                self.popup.take()
+            }
+            PopupTypes::ExternalChangePopup => {
+                let doc = self.documents.get_mut(self.active)?;
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Char('r'), KeyModifiers::NONE) => {
+                        doc.reload()
+                            .err()
+                            .map(|e| Popup::new(e.to_string(), PopupTypes::ErrorPopup))
+                    }
+                    (KeyCode::Char('o'), KeyModifiers::NONE) => {
+                        doc.save_file(&self.backup)
+                            .err()
+                            .map(|e| Popup::new(e.to_string(), PopupTypes::ErrorPopup))
+                    }
+                    (KeyCode::Char('d'), KeyModifiers::NONE) => {
+                        let on_disk = diff::read_normalized(&doc.file_path);
+                        let summary = diff::diff_summary(&on_disk, &doc.content.to_string(), 15);
+                        popup.msg = external_change_prompt(&doc.file_path, Some(summary));
+                        Some(popup)
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => None,
+                    _ => Some(popup),
+                }
+            }
+            PopupTypes::RecoverSwapPopup => {
+                if self.pending_swaps.is_empty() {
+                    return None;
+                }
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Char('r'), KeyModifiers::NONE) => {
+                        let pending = self.pending_swaps.remove(0);
+                        if let Err(e) = self.recover_swap(&pending) {
+                            return Some(Popup::new(e.to_string(), PopupTypes::ErrorPopup));
+                        }
+                    }
+                    (KeyCode::Char('d'), KeyModifiers::NONE) => {
+                        let diff = swap::diff_preview(&self.pending_swaps[0], 15);
+                        popup.msg = swap_prompt(&self.pending_swaps[0], Some(diff));
+                        return Some(popup);
+                    }
+                    (KeyCode::Char('x'), KeyModifiers::NONE) => {
+                        let pending = self.pending_swaps.remove(0);
+                        swap::remove_swap(&pending.file_path);
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        // Keep the swap file on disk, it is offered again next start
+                        self.pending_swaps.remove(0);
+                    }
+                    _ => return Some(popup),
+                }
+                self.show_next_swap();
+                self.popup.take()
+            }
+            PopupTypes::ReplaceConfirmPopup => {
+                // The undo group opened for the substitution is closed even
+                // when there is nothing left to confirm
+                let (sub, doc) = match (self.substitution.take(), self.documents.get_mut(self.active)) {
+                    (Some(sub), Some(doc)) => (sub, doc),
+                    _ => {
+                        self.finish_substitution();
+                        return None;
+                    }
+                };
+                let index = doc.state.current_match;
+                let total = doc.state.highlights.len();
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Char('y'), KeyModifiers::NONE) => {
+                        if let Some(m) = doc.state.highlights.get(index).copied() {
+                            let delta = doc.substitute_at(&sub, m);
+                            let shift = |col: usize| (col as isize + delta) as usize;
+                            // Later matches on the same line moved with the replacement
+                            for h in doc.state.highlights.iter_mut().skip(index + 1).filter(|h| h.0 == m.0) {
+                                *h = (h.0, shift(h.1), shift(h.2));
+                            }
+                            doc.state.highlights[index].2 = shift(m.2);
+                        }
+                        doc.state.current_match += 1;
+                    }
+                    (KeyCode::Char('n'), KeyModifiers::NONE) => doc.state.current_match += 1,
+                    (KeyCode::Char('a'), KeyModifiers::NONE) => {
+                        let rest = doc.state.highlights[index.min(total)..].to_vec();
+                        for m in rest.iter().rev() {
+                            doc.substitute_at(&sub, *m);
+                        }
+                        doc.state.current_match = total;
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE) | (KeyCode::Esc, KeyModifiers::NONE) => {
+                        doc.state.current_match = total;
+                    }
+                    _ => {
+                        self.substitution = Some(sub);
+                        return Some(popup);
+                    }
+                }
+                self.substitution = Some(sub);
+                self.prompt_substitution()
+            }
+            PopupTypes::FileFinderPopup => {
+                let finder = self.finder.as_mut()?;
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                        finder.query.push(c);
+                        finder.update();
+                        Some(popup)
+                    }
+                    (KeyCode::Backspace, KeyModifiers::NONE) => {
+                        finder.query.pop();
+                        finder.update();
+                        Some(popup)
+                    }
+                    (KeyCode::Down, KeyModifiers::NONE) => {
+                        if finder.selected + 1 < finder.matches.len() {
+                            finder.selected += 1;
+                        }
+                        Some(popup)
+                    }
+                    (KeyCode::Up, KeyModifiers::NONE) => {
+                        finder.selected = finder.selected.saturating_sub(1);
+                        Some(popup)
+                    }
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        let path = finder.selected_path();
+                        self.finder = None;
+                        let path = path?.to_string_lossy().to_string();
+                        self.focus = Windows::Editor;
+                        self.open_or_switch(&path)
+                            .err()
+                            .map(|e| Popup::new(e.to_string(), PopupTypes::ErrorPopup))
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        self.finder = None;
+                        None
+                    }
+                    _ => Some(popup),
+                }
+            }
+            PopupTypes::CommandPalettePopup => {
+                let palette = self.palette.as_mut()?;
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                        palette.query.push(c);
+                        palette.update();
+                        Some(popup)
+                    }
+                    (KeyCode::Backspace, KeyModifiers::NONE) => {
+                        palette.query.pop();
+                        palette.update();
+                        Some(popup)
+                    }
+                    (KeyCode::Down, KeyModifiers::NONE) => {
+                        if palette.selected + 1 < palette.matches.len() {
+                            palette.selected += 1;
+                        }
+                        Some(popup)
+                    }
+                    (KeyCode::Up, KeyModifiers::NONE) => {
+                        palette.selected = palette.selected.saturating_sub(1);
+                        Some(popup)
+                    }
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        let action = palette.selected_action();
+                        self.palette = None;
+                        // The action may open a popup of its own
+                        (action?.run)(self);
+                        self.popup.take()
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        self.palette = None;
+                        None
+                    }
+                    _ => Some(popup),
+                }
+            }
+            PopupTypes::CompletionPopup => {
+                let completion = self.completion.as_ref()?;
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Down, KeyModifiers::NONE) => {
+                        self.selected_completion = (self.selected_completion + 1) % completion.candidates.len();
+                        Some(popup)
+                    }
+                    (KeyCode::Up, KeyModifiers::NONE) => {
+                        self.selected_completion = self.selected_completion.saturating_sub(1);
+                        Some(popup)
+                    }
+                    (KeyCode::Enter | KeyCode::Tab, KeyModifiers::NONE) => {
+                        let completion = self.completion.take()?;
+                        let text = completion.candidates.get(self.selected_completion)?;
+                        self.insert_completion(completion.start, completion.end, text);
+                        None
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        self.completion = None;
+                        None
+                    }
+                    // Any other key goes on editing the command line
+                    _ => {
+                        self.completion = None;
+                        self.handle_key_event(key_event);
+                        self.popup.take()
+                    }
+                }
+            }
+            PopupTypes::UndoTreePopup => {
+                let doc = self.documents.get_mut(self.active)?;
+                let states = doc.state.undo_stack.states();
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Down, KeyModifiers::NONE) => {
+                        if self.selected_undo + 1 < states.len() {
+                            self.selected_undo += 1;
+                        }
+                        Some(popup)
+                    }
+                    (KeyCode::Up, KeyModifiers::NONE) => {
+                        self.selected_undo = self.selected_undo.saturating_sub(1);
+                        Some(popup)
+                    }
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        let target = states.get(self.selected_undo)?.id;
+                        if let Err(e) = doc.undo_jump(target) {
+                            return Some(Popup::new(e.to_string(), PopupTypes::ErrorPopup));
+                        }
+                        popup.msg = undo_tree_text(&doc.state.undo_stack.states());
+                        Some(popup)
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => None,
+                    _ => Some(popup),
+                }
+            }
+            PopupTypes::OutlinePopup => {
+                match (key_event.code, key_event.modifiers) {
+                    (KeyCode::Down, KeyModifiers::NONE) => {
+                        if self.selected_outline + 1 < self.outline.len() {
+                            self.selected_outline += 1;
+                        }
+                        Some(popup)
+                    }
+                    (KeyCode::Up, KeyModifiers::NONE) => {
+                        self.selected_outline = self.selected_outline.saturating_sub(1);
+                        Some(popup)
+                    }
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        let item = self.outline.get(self.selected_outline)?.clone();
+                        let doc = self.documents.get_mut(self.active)?;
+                        doc.state.selection = None;
+                        doc.adjust_cursor(item.line, item.col, false);
+                        None
+                    }
+                    (KeyCode::Esc, KeyModifiers::NONE) => None,
+                    _ => Some(popup),
+                }
             }
         }
     }
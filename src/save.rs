@@ -0,0 +1,100 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::fs::{chown, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::data_models::BackupPolicy;
+
+// Writes a file through a temporary sibling that is fsynced and renamed over
+// the target, so a crash mid-write never leaves a truncated file behind.
+pub fn write_atomic<F>(path: &Path, backup: &BackupPolicy, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    // Follow symlinks so the link itself is not replaced by a regular file
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match target.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let original = fs::metadata(&target).ok();
+
+    let tmp = dir.join(format!(".{}.tpad-{}.tmp", name, std::process::id()));
+    let result = write_tmp(&tmp, original.as_ref(), write).and_then(|_| {
+        if let (Some(_), Some(backup_path)) = (&original, backup_path(&target, backup)) {
+            fs::copy(&target, backup_path)?;
+        }
+        fs::rename(&tmp, &target)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+
+    // Persist the rename itself, not every filesystem supports this
+    if let Ok(d) = File::open(&dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+fn write_tmp<F>(tmp: &Path, original: Option<&fs::Metadata>, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let file = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    writer.flush()?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    if let Some(meta) = original {
+        fs::set_permissions(tmp, meta.permissions())?;
+        // Only root can give files away, keep going if this fails
+        let _ = chown(tmp, Some(meta.uid()), Some(meta.gid()));
+    }
+    Ok(())
+}
+
+// Where the previous version of `target` is copied before it gets replaced.
+pub fn backup_path(target: &Path, backup: &BackupPolicy) -> Option<PathBuf> {
+    match backup {
+        BackupPolicy::Off => None,
+        BackupPolicy::SameDir => {
+            let mut name = target.as_os_str().to_owned();
+            name.push("~");
+            Some(PathBuf::from(name))
+        }
+        BackupPolicy::Dir(dir) => {
+            // Encode the full path in the name so equal file names don't collide
+            Some(dir.join(format!("{}~", flat_file_name(&target.to_string_lossy()))))
+        }
+    }
+}
+
+// A path as one file name, escaped the way URLs are so no two paths give the same name.
+pub fn flat_file_name(path: &str) -> String {
+    path.replace('%', "%25").replace(std::path::MAIN_SEPARATOR, "%2F")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_names_are_distinct() {
+        let names = ["/a/b%c", "/a%b/c", "/a/%b", "/a%/b", "/a%2Fb"].map(flat_file_name);
+        for (i, name) in names.iter().enumerate() {
+            assert!(!name.contains('/'));
+            assert!(names[i + 1..].iter().all(|other| other != name), "{} repeats", name);
+        }
+        assert_eq!(flat_file_name("/a/b%c"), "%2Fa%2Fb%25c");
+    }
+}
@@ -0,0 +1,175 @@
+use std::error::Error;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::data_models::Substitution;
+
+// Compiles a search typed as `pattern` or `pattern/flags`.
+// Flags: `i` ignores case, `w` only matches whole words. A `/` inside the
+// pattern can be written as `\/`.
+pub fn parse_search(input: &str) -> Result<Regex, Box<dyn Error>> {
+    let (pattern, flags) = split_flags(input);
+    compile(pattern, flags)
+}
+
+fn compile(pattern: &str, flags: &str) -> Result<Regex, Box<dyn Error>> {
+    if pattern.is_empty() {
+        return Err("Empty search pattern".into());
+    }
+    let source = pattern.replace("\\/", "/");
+    let source = if flags.contains('w') {
+        format!(r"\b(?:{})\b", source)
+    } else {
+        source
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(flags.contains('i'))
+        .build()
+        .map_err(|e| format!("Invalid pattern '{}': {}", pattern, describe_error(&e)).into())
+}
+
+// Parses `s/pattern/replacement/flags`, prefixed with `%` for the whole document.
+// Besides the search flags, `g` replaces every match on a line instead of the
+// first one and `c` asks before each replacement.
+pub fn parse_substitute(input: &str) -> Result<Substitution, Box<dyn Error>> {
+    let input = input.trim_start();
+    let (whole_doc, rest) = match input.strip_prefix('%') {
+        Some(rest) => (true, rest),
+        None => (false, input),
+    };
+    let rest = rest
+        .strip_prefix("s/")
+        .ok_or("Substitute expects s/pattern/replacement/flags")?;
+    let parts = split_unescaped(rest);
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err("Substitute expects s/pattern/replacement/flags".into());
+    }
+    let flags = parts.get(2).copied().unwrap_or("");
+    if let Some(flag) = flags.chars().find(|c| !"gciw".contains(*c)) {
+        return Err(format!("Unknown substitute flag '{}'", flag).into());
+    }
+    Ok(Substitution {
+        pattern: compile(parts[0], flags)?,
+        replacement: replacement_template(parts[1]),
+        whole_doc,
+        global: flags.contains('g'),
+        confirm: flags.contains('c'),
+    })
+}
+
+// Splits on every `/` that is not escaped with a backslash.
+fn split_unescaped(input: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '/' if !escaped => {
+                parts.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+// Turns vim style `\1` group references into the `${1}` form the regex crate
+// expands, `$1` and `${name}` work as they are. `\/` and `\\` are unescaped.
+fn replacement_template(replacement: &str) -> String {
+    let mut template = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(d)) if d.is_ascii_digit() => {
+                chars.next();
+                template.push_str(&format!("${{{}}}", d));
+            }
+            ('\\', Some(d)) if d == '/' || d == '\\' => {
+                chars.next();
+                template.push(d);
+            }
+            _ => template.push(c),
+        }
+    }
+    template
+}
+
+// Splits off the flags after the last unescaped `/`. Text after it only
+// counts as flags when it is made of flag letters, so `a/b` stays a pattern.
+fn split_flags(input: &str) -> (&str, &str) {
+    let mut split = None;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '/' if !escaped => split = Some(i),
+            _ => escaped = false,
+        }
+    }
+    match split {
+        Some(i) if input[i + 1..].chars().all(|c| c == 'i' || c == 'w') => (&input[..i], &input[i + 1..]),
+        _ => (input, ""),
+    }
+}
+
+// The regex crate reports syntax errors over several lines with a caret
+// under the problem, only the final explanation fits in a popup.
+fn describe_error(error: &regex::Error) -> String {
+    let text = error.to_string();
+    text.lines()
+        .rev()
+        .find_map(|line| line.strip_prefix("error: "))
+        .unwrap_or(text.lines().last().unwrap_or(""))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_flags_only_when_they_are_flags() {
+        assert!(parse_search("a/b").unwrap().is_match("a/b"));
+        assert!(parse_search("abc/i").unwrap().is_match("ABC"));
+    }
+
+    #[test]
+    fn substitute_parts_and_flags() {
+        let sub = parse_substitute("%s/a\\/b/x/gc").unwrap();
+        assert!(sub.whole_doc && sub.global && sub.confirm);
+        assert!(sub.pattern.is_match("a/b"));
+        assert_eq!(sub.replacement, "x");
+
+        let sub = parse_substitute("s/Foo/bar/").unwrap();
+        assert!(!sub.whole_doc && !sub.global && !sub.confirm);
+        let sub = parse_substitute("s/Foo/bar/i").unwrap();
+        assert!(sub.pattern.is_match("foo"));
+        let sub = parse_substitute("s/cat/dog/w").unwrap();
+        assert!(!sub.pattern.is_match("concat"));
+        // The trailing slash is optional
+        assert!(parse_substitute("s/a/b").is_ok());
+    }
+
+    #[test]
+    fn substitute_errors() {
+        assert!(parse_substitute("s/a/b/q").is_err());
+        assert!(parse_substitute("x/a/b/").is_err());
+        assert!(parse_substitute("s/a").is_err());
+        assert!(parse_substitute("s//b/").is_err());
+        assert!(parse_substitute("s/(/b/").is_err());
+    }
+
+    #[test]
+    fn replacement_groups_and_escapes() {
+        assert_eq!(replacement_template("\\1-\\2"), "${1}-${2}");
+        assert_eq!(replacement_template("a\\/b\\\\c"), "a/b\\c");
+        // Only digits, `/` and `\` are escapes, others stay as typed
+        assert_eq!(replacement_template("\\n"), "\\n");
+        assert_eq!(replacement_template("\\10"), "${1}0");
+        let sub = parse_substitute("s/(\\w+) (\\w+)/\\2 \\1/").unwrap();
+        assert_eq!(sub.pattern.replace("hello world", sub.replacement.as_str()), "world hello");
+    }
+}
@@ -9,17 +9,44 @@ fn get_session_file_path() -> PathBuf {
     base_dir.join("tpad").join("session.json")
 }
 
+fn get_history_file_path() -> PathBuf {
+    let base_dir = config_dir().unwrap_or_else(|| PathBuf::from("."));
+    base_dir.join("tpad").join("history.json")
+}
+
+pub fn save_history(history: &CommandHistory) -> Result<(), Box<dyn Error>> {
+    let path = get_history_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(history)?)?;
+    Ok(())
+}
+pub fn load_history() -> CommandHistory {
+    fs::read_to_string(get_history_file_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
 pub fn save_session(app: &mut App) -> Result<(), Box<dyn Error>> {
     let session = SavedSession {
+        // Grep results have no file to reopen
         saved_files: app
             .documents
             .iter()
+            .filter(|doc| !doc.is_read_only())
             .map(|doc| doc.file_path.clone())
             .collect(),
-        undo_bufs: app
+        undo_history: app
             .documents
             .iter()
-            .map(|doc| doc.state.undo_stack.clone())
+            .filter(|doc| !doc.is_read_only())
+            .map(|doc| SavedUndo {
+                file_path: doc.file_path.clone(),
+                content_hash: doc.buffer_hash(),
+                stack: doc.state.undo_stack.clone(),
+            })
             .collect(),
         active: app.active,
     };
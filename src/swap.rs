@@ -0,0 +1,95 @@
+use dirs::config_dir;
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{data_models::*, diff, save};
+
+// How often unsaved documents are written to their swap files.
+pub const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+
+fn get_swap_dir() -> PathBuf {
+    let base_dir = config_dir().unwrap_or_else(|| PathBuf::from("."));
+    base_dir.join("tpad").join("swap")
+}
+
+// One swap file per document, named after its full path.
+pub fn swap_file_path(file_path: &str) -> PathBuf {
+    let absolute = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+    get_swap_dir().join(format!("{}.swp", save::flat_file_name(&absolute.to_string_lossy())))
+}
+
+pub fn write_swap(doc: &Document) -> Result<(), Box<dyn Error>> {
+    let swap = SwapFile {
+        file_path: doc.file_path.clone(),
+        content: doc.content.to_string(),
+        saved_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        pid: std::process::id(),
+    };
+    let path = swap_file_path(&doc.file_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string(&swap)?;
+    fs::write(path, json)?;
+    Ok(())
+}
+
+// Writes swap files for every document edited since the last run.
+pub fn write_swaps(app: &mut App) {
+    for doc in app.documents.iter_mut() {
+        if doc.state.swap_pending && doc.state.is_dirty && write_swap(doc).is_ok() {
+            doc.state.swap_pending = false;
+        }
+    }
+}
+
+pub fn remove_swap(file_path: &str) {
+    let _ = fs::remove_file(swap_file_path(file_path));
+}
+
+// Swap files left behind by a session that did not exit cleanly.
+pub fn load_swaps() -> Vec<SwapFile> {
+    let entries = match fs::read_dir(get_swap_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter_map(|json| serde_json::from_str::<SwapFile>(&json).ok())
+        .filter(|swap| !owner_is_running(swap.pid))
+        .collect()
+}
+
+// Another running tpad still owns this swap file, leave it alone.
+fn owner_is_running(pid: u32) -> bool {
+    pid != std::process::id() && Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+pub fn describe_age(saved_at: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(saved_at);
+    let secs = now.saturating_sub(saved_at);
+    match secs {
+        0..=59 => format!("{} seconds ago", secs),
+        60..=3599 => format!("{} minutes ago", secs / 60),
+        3600..=86399 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+// Changed lines between the file on disk and the swap contents.
+pub fn diff_preview(swap: &SwapFile, max_lines: usize) -> String {
+    let on_disk = diff::read_normalized(&swap.file_path);
+    diff::diff_summary(&on_disk, &swap.content, max_lines)
+}
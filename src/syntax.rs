@@ -0,0 +1,468 @@
+use std::path::Path;
+
+use crate::data_models::*;
+
+// How a language is tokenized. Languages are plain tables, the tokenizer in
+// `tokenize` is shared by all of them.
+pub struct Language {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    // Interpreters named on a `#!` line
+    pub shebangs: &'static [&'static str],
+    pub keywords: &'static [&'static str],
+    pub types: &'static [&'static str],
+    pub constants: &'static [&'static str],
+    // Identifiers starting with an uppercase letter are types
+    pub capitalized_types: bool,
+    pub line_comments: &'static [&'static str],
+    pub block_comment: Option<(&'static str, &'static str)>,
+    // String delimiters, longest first. Those in `multiline_strings` carry
+    // on to the next line when left open.
+    pub strings: &'static [&'static str],
+    pub multiline_strings: &'static [&'static str],
+    // Quote of char literals like 'a', told apart from Rust lifetimes
+    pub char_quote: Option<char>,
+    // `name!` is a macro call
+    pub bang_macros: bool,
+}
+
+pub const LANGUAGES: &[Language] = &[
+    Language {
+        name: "Rust",
+        extensions: &["rs"],
+        shebangs: &[],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "fn", "for",
+            "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "static",
+            "struct", "super", "trait", "type", "unsafe", "use", "where", "while",
+        ],
+        types: &[
+            "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize", "f32", "f64", "bool",
+            "char", "str",
+        ],
+        constants: &["true", "false", "None", "Some", "Ok", "Err"],
+        capitalized_types: true,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\""],
+        multiline_strings: &["\""],
+        char_quote: Some('\''),
+        bang_macros: true,
+    },
+    Language {
+        name: "Python",
+        extensions: &["py", "pyw"],
+        shebangs: &["python", "python2", "python3"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "case", "class", "continue", "def", "del", "elif", "else",
+            "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "match", "nonlocal",
+            "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+        ],
+        types: &["int", "float", "str", "bool", "list", "dict", "set", "tuple", "bytes", "object"],
+        constants: &["True", "False", "None", "self"],
+        capitalized_types: true,
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &["\"\"\"", "'''", "\"", "'"],
+        multiline_strings: &["\"\"\"", "'''"],
+        char_quote: None,
+        bang_macros: false,
+    },
+    Language {
+        name: "C/C++",
+        extensions: &["c", "h", "cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+        shebangs: &[],
+        keywords: &[
+            "auto", "break", "case", "catch", "class", "const", "constexpr", "continue", "default", "delete", "do",
+            "else", "enum", "extern", "for", "friend", "goto", "if", "inline", "namespace", "new", "operator",
+            "override", "private", "protected", "public", "register", "restrict", "return", "sizeof", "static",
+            "struct", "switch", "template", "this", "throw", "try", "typedef", "typename", "union", "using", "virtual",
+            "volatile", "while",
+        ],
+        types: &[
+            "void", "char", "short", "int", "long", "float", "double", "signed", "unsigned", "bool", "size_t",
+            "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t", "uint32_t", "uint64_t",
+        ],
+        constants: &["true", "false", "NULL", "nullptr"],
+        capitalized_types: false,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\""],
+        multiline_strings: &[],
+        char_quote: Some('\''),
+        bang_macros: false,
+    },
+    Language {
+        name: "JavaScript",
+        extensions: &["js", "mjs", "cjs", "jsx", "ts", "tsx"],
+        shebangs: &["node", "deno"],
+        keywords: &[
+            "as", "async", "await", "break", "case", "catch", "class", "const", "continue", "debugger", "declare",
+            "default", "delete", "do", "else", "enum", "export", "extends", "finally", "for", "from", "function", "if",
+            "implements", "import", "in", "instanceof", "interface", "let", "new", "of", "readonly", "return",
+            "static", "super", "switch", "this", "throw", "try", "type", "typeof", "var", "void", "while", "yield",
+        ],
+        types: &["string", "number", "boolean", "any", "unknown", "never", "object"],
+        constants: &["true", "false", "null", "undefined", "NaN", "Infinity"],
+        capitalized_types: true,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "'", "`"],
+        multiline_strings: &["`"],
+        char_quote: None,
+        bang_macros: false,
+    },
+    Language {
+        name: "Go",
+        extensions: &["go"],
+        shebangs: &[],
+        keywords: &[
+            "break", "case", "chan", "const", "continue", "default", "defer", "else", "fallthrough", "for", "func",
+            "go", "goto", "if", "import", "interface", "map", "package", "range", "return", "select", "struct",
+            "switch", "type", "var",
+        ],
+        types: &[
+            "any", "bool", "byte", "complex64", "complex128", "error", "float32", "float64", "int", "int8", "int16",
+            "int32", "int64", "rune", "string", "uint", "uint8", "uint16", "uint32", "uint64", "uintptr",
+        ],
+        constants: &["true", "false", "nil", "iota"],
+        capitalized_types: false,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &["\"", "`"],
+        multiline_strings: &["`"],
+        char_quote: Some('\''),
+        bang_macros: false,
+    },
+    Language {
+        name: "Shell",
+        extensions: &["sh", "bash", "zsh"],
+        shebangs: &["sh", "bash", "zsh", "dash", "ksh"],
+        keywords: &[
+            "if", "then", "else", "elif", "fi", "case", "esac", "for", "select", "while", "until", "do", "done", "in",
+            "function", "return", "exit", "export", "local", "readonly", "declare", "unset", "break", "continue",
+            "source",
+        ],
+        types: &[],
+        constants: &["true", "false"],
+        capitalized_types: false,
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &["\"", "'"],
+        multiline_strings: &["\"", "'"],
+        char_quote: None,
+        bang_macros: false,
+    },
+    Language {
+        name: "TOML",
+        extensions: &["toml"],
+        shebangs: &[],
+        keywords: &[],
+        types: &[],
+        constants: &["true", "false"],
+        capitalized_types: false,
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &["\"\"\"", "'''", "\"", "'"],
+        multiline_strings: &["\"\"\"", "'''"],
+        char_quote: None,
+        bang_macros: false,
+    },
+    Language {
+        name: "JSON",
+        extensions: &["json"],
+        shebangs: &[],
+        keywords: &[],
+        types: &[],
+        constants: &["true", "false", "null"],
+        capitalized_types: false,
+        line_comments: &[],
+        block_comment: None,
+        strings: &["\""],
+        multiline_strings: &[],
+        char_quote: None,
+        bang_macros: false,
+    },
+];
+
+// The language of a file, by extension and else by the interpreter on its `#!` line.
+pub fn detect(path: &Path, first_line: &str) -> Option<&'static Language> {
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        let ext = ext.to_lowercase();
+        if let Some(language) = LANGUAGES.iter().find(|l| l.extensions.contains(&ext.as_str())) {
+            return Some(language);
+        }
+    }
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    // "#!/usr/bin/env python3" names the interpreter after env
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-'))?;
+    }
+    LANGUAGES.iter().find(|l| l.shebangs.contains(&program))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Byte offset after the string closed by `delim` starting the search at
+// `from`, skipping backslash escapes. None when the line ends first.
+fn string_end(line: &str, from: usize, delim: &str) -> Option<usize> {
+    let mut chars = line[from..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if line[from + i..].starts_with(delim) {
+            return Some(from + i + delim.len());
+        }
+    }
+    None
+}
+
+// Tokens of one line as byte ranges, given the state the previous line ended
+// in, and the state this one ends in.
+pub fn tokenize(language: &Language, line: &str, state: LineState) -> (Vec<(usize, usize, TokenKind)>, LineState) {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    // Finish what the previous line left open
+    match state {
+        LineState::Normal => {}
+        LineState::Comment => {
+            let Some((_, close)) = language.block_comment else {
+                return (tokens, LineState::Normal);
+            };
+            match line.find(close) {
+                Some(end) => {
+                    i = end + close.len();
+                    tokens.push((0, i, TokenKind::Comment));
+                }
+                None => {
+                    tokens.push((0, line.len(), TokenKind::Comment));
+                    return (tokens, LineState::Comment);
+                }
+            }
+        }
+        LineState::Str(delim) => match string_end(line, 0, delim) {
+            Some(end) => {
+                i = end;
+                tokens.push((0, i, TokenKind::String));
+            }
+            None => {
+                tokens.push((0, line.len(), TokenKind::String));
+                return (tokens, LineState::Str(delim));
+            }
+        },
+    }
+
+    while i < line.len() {
+        let rest = &line[i..];
+        let c = rest.chars().next().unwrap_or(' ');
+        let after_space = i == 0 || line[..i].ends_with(char::is_whitespace);
+
+        // A `#` comment has to start a word, `$#` in a shell script is not one
+        if let Some(marker) = language.line_comments.iter().find(|m| rest.starts_with(*m))
+            && (!marker.starts_with('#') || after_space)
+        {
+            tokens.push((i, line.len(), TokenKind::Comment));
+            return (tokens, LineState::Normal);
+        }
+        if let Some((open, close)) = language.block_comment
+            && rest.starts_with(open)
+        {
+            match line[i + open.len()..].find(close) {
+                Some(end) => {
+                    let end = i + open.len() + end + close.len();
+                    tokens.push((i, end, TokenKind::Comment));
+                    i = end;
+                    continue;
+                }
+                None => {
+                    tokens.push((i, line.len(), TokenKind::Comment));
+                    return (tokens, LineState::Comment);
+                }
+            }
+        }
+        if let Some(delim) = language.strings.iter().find(|d| rest.starts_with(*d)) {
+            match string_end(line, i + delim.len(), delim) {
+                Some(end) => {
+                    tokens.push((i, end, TokenKind::String));
+                    i = end;
+                    continue;
+                }
+                None => {
+                    tokens.push((i, line.len(), TokenKind::String));
+                    let next = if language.multiline_strings.contains(delim) { LineState::Str(delim) } else { LineState::Normal };
+                    return (tokens, next);
+                }
+            }
+        }
+        if Some(c) == language.char_quote {
+            // 'a' and '\n' are chars, 'a on its own is a lifetime
+            let end = if rest[1..].starts_with('\\') {
+                string_end(line, i + 1, "'")
+            } else {
+                rest[1..].char_indices().nth(1).filter(|(_, q)| *q == '\'').map(|(j, _)| i + 1 + j + 1)
+            };
+            if let Some(end) = end {
+                tokens.push((i, end, TokenKind::String));
+                i = end;
+                continue;
+            }
+        }
+        if c.is_ascii_digit() {
+            let len = rest.find(|ch: char| !(is_word_char(ch) || ch == '.')).unwrap_or(rest.len());
+            tokens.push((i, i + len, TokenKind::Number));
+            i += len;
+            continue;
+        }
+        if is_word_char(c) {
+            let len = rest.find(|ch: char| !is_word_char(ch)).unwrap_or(rest.len());
+            let word = &rest[..len];
+            let next = rest[len..].trim_start().chars().next();
+            let kind = if language.keywords.contains(&word) {
+                Some(TokenKind::Keyword)
+            } else if language.constants.contains(&word) {
+                Some(TokenKind::Constant)
+            } else if language.types.contains(&word) || (language.capitalized_types && c.is_uppercase()) {
+                Some(TokenKind::Type)
+            } else if next == Some('(') || (language.bang_macros && rest[len..].starts_with('!')) {
+                // Calls, and macro invocations in Rust
+                Some(TokenKind::Function)
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                tokens.push((i, i + len, kind));
+            }
+            i += len;
+            continue;
+        }
+        i += c.len_utf8();
+    }
+    (tokens, LineState::Normal)
+}
+
+impl Highlighter {
+    pub fn new(language: &'static Language) -> Highlighter {
+        Highlighter {
+            language,
+            states: vec![LineState::Normal],
+        }
+    }
+    // State line `line` starts in, Normal when it was not computed yet.
+    pub fn state_at(&self, line: usize) -> LineState {
+        self.states.get(line).copied().unwrap_or(LineState::Normal)
+    }
+}
+
+impl Document {
+    // Brings the tokenizer states up to date for the lines before `upto`. Only
+    // lines from the first edit since the last call are tokenized again.
+    pub fn refresh_syntax(&mut self, upto: usize) {
+        let edited = self.content.take_edited_from();
+        let Some(syntax) = &mut self.syntax else {
+            return;
+        };
+        if let Some(line) = edited {
+            syntax.states.truncate(line + 1);
+        }
+        let upto = upto.min(self.content.len_lines());
+        while syntax.states.len() < upto {
+            let line = syntax.states.len() - 1;
+            let (_, end) = tokenize(syntax.language, &self.content.line(line), syntax.states[line]);
+            syntax.states.push(end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(name: &str) -> &'static Language {
+        LANGUAGES.iter().find(|l| l.name == name).unwrap()
+    }
+
+    // Tokens as the text they cover, easier to read than byte ranges
+    fn tokens<'a>(language: &Language, line: &'a str, state: LineState) -> (Vec<(&'a str, TokenKind)>, LineState) {
+        let (tokens, next) = tokenize(language, line, state);
+        (tokens.into_iter().map(|(start, end, kind)| (&line[start..end], kind)).collect(), next)
+    }
+
+    #[test]
+    fn detect_by_extension_then_shebang() {
+        let name = |path: &str, first_line: &str| detect(Path::new(path), first_line).map(|l| l.name);
+        assert_eq!(name("src/main.rs", ""), Some("Rust"));
+        assert_eq!(name("CONFIG.TOML", ""), Some("TOML"));
+        // The extension wins over the `#!` line
+        assert_eq!(name("tool.py", "#!/bin/sh"), Some("Python"));
+        assert_eq!(name("tool", "#!/usr/bin/env python3"), Some("Python"));
+        assert_eq!(name("build", "#!/usr/bin/env -S bash -e"), Some("Shell"));
+        assert_eq!(name("run", "#!/bin/zsh"), Some("Shell"));
+        assert_eq!(name("notes.txt", "#!/bin/sh"), Some("Shell"));
+        assert_eq!(name("notes.txt", "hello"), None);
+        assert_eq!(name("tool", "#!/usr/bin/perl"), None);
+    }
+
+    #[test]
+    fn block_comments_span_lines() {
+        let rust = language("Rust");
+        let (toks, state) = tokens(rust, "let x = 1; /* open", LineState::Normal);
+        assert_eq!(toks.last(), Some(&("/* open", TokenKind::Comment)));
+        assert_eq!(state, LineState::Comment);
+        let (toks, state) = tokens(rust, "still open", state);
+        assert_eq!(toks, [("still open", TokenKind::Comment)]);
+        assert_eq!(state, LineState::Comment);
+        let (toks, state) = tokens(rust, "closed */ fn", state);
+        assert_eq!(toks, [("closed */", TokenKind::Comment), ("fn", TokenKind::Keyword)]);
+        assert_eq!(state, LineState::Normal);
+    }
+
+    #[test]
+    fn strings_span_lines_where_the_language_allows() {
+        let rust = language("Rust");
+        let (toks, state) = tokens(rust, r#"let s = "one"#, LineState::Normal);
+        assert_eq!(toks.last(), Some(&("\"one", TokenKind::String)));
+        assert_eq!(state, LineState::Str("\""));
+        // An escaped quote does not close the string
+        let (toks, state) = tokens(rust, r#"t\"wo" as"#, state);
+        assert_eq!(toks, [(r#"t\"wo""#, TokenKind::String), ("as", TokenKind::Keyword)]);
+        assert_eq!(state, LineState::Normal);
+
+        let python = language("Python");
+        let (_, state) = tokens(python, r#"doc = """first"#, LineState::Normal);
+        assert_eq!(state, LineState::Str("\"\"\""));
+        let (toks, state) = tokens(python, r#"last""" + 1"#, state);
+        assert_eq!(toks, [(r#"last""""#, TokenKind::String), ("1", TokenKind::Number)]);
+        assert_eq!(state, LineState::Normal);
+        // A plain Python string ends with its line
+        let (_, state) = tokens(python, r#"s = "open"#, LineState::Normal);
+        assert_eq!(state, LineState::Normal);
+    }
+
+    #[test]
+    fn hash_comments_only_where_the_language_has_them() {
+        for name in ["Python", "Shell", "TOML"] {
+            let (toks, _) = tokens(language(name), "x = 1 # note", LineState::Normal);
+            assert_eq!(toks.last(), Some(&("# note", TokenKind::Comment)), "{}", name);
+        }
+        // `$#` is the argument count, not a comment
+        let (toks, _) = tokens(language("Shell"), "echo $# # count", LineState::Normal);
+        let comments: Vec<&str> = toks.iter().filter(|(_, kind)| *kind == TokenKind::Comment).map(|(text, _)| *text).collect();
+        assert_eq!(comments, ["# count"]);
+        let (toks, _) = tokens(language("Rust"), "#[derive(Debug)]", LineState::Normal);
+        assert!(toks.iter().all(|(_, kind)| *kind != TokenKind::Comment));
+    }
+
+    #[test]
+    fn lifetimes_are_not_char_literals() {
+        let rust = language("Rust");
+        let (toks, _) = tokens(rust, "fn f<'a>(s: &'a str) -> char { 'a' }", LineState::Normal);
+        let strings: Vec<&str> = toks.iter().filter(|(_, kind)| *kind == TokenKind::String).map(|(text, _)| *text).collect();
+        assert_eq!(strings, ["'a'"]);
+        let (toks, _) = tokens(rust, r"let c = '\n';", LineState::Normal);
+        assert!(toks.contains(&(r"'\n'", TokenKind::String)));
+    }
+}
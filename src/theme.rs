@@ -4,7 +4,7 @@ use dirs::config_dir;
 use serde::{Deserialize, Deserializer, Serialize};
 use ratatui::style::Color;
 use serde_json::from_str;
-use crate::{ Popup};
+use crate::{ Popup, TokenKind};
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Theme {
     pub status: StatusSytle,
@@ -12,6 +12,50 @@ pub struct Theme {
     pub editor: EditorStyle,
     pub command: CommandStyle,
     pub popup: PopupStyle,
+    // Themes written before syntax highlighting existed get the default colors
+    #[serde(default)]
+    pub syntax: SyntaxStyle,
+}
+
+// Colors of the token classes syntax highlighting picks out.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SyntaxStyle {
+    pub keyword: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub string: String,
+    pub comment: String,
+    pub number: String,
+    pub constant: String,
+    pub function: String,
+}
+
+impl Default for SyntaxStyle {
+    fn default() -> Self {
+        SyntaxStyle {
+            keyword: String::from("#c678dd"),
+            type_name: String::from("#e5c07b"),
+            string: String::from("#98c379"),
+            comment: String::from("#7f848e"),
+            number: String::from("#d19a66"),
+            constant: String::from("#d19a66"),
+            function: String::from("#61afef"),
+        }
+    }
+}
+
+impl SyntaxStyle {
+    pub fn color(&self, kind: TokenKind) -> &String {
+        match kind {
+            TokenKind::Keyword => &self.keyword,
+            TokenKind::Type => &self.type_name,
+            TokenKind::String => &self.string,
+            TokenKind::Comment => &self.comment,
+            TokenKind::Number => &self.number,
+            TokenKind::Constant => &self.constant,
+            TokenKind::Function => &self.function,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -70,6 +114,7 @@ impl Default for Theme {
             tabs : TabsStyle::default(),
             status : StatusSytle::default(),
             popup : PopupStyle::default(),
+            syntax: SyntaxStyle::default(),
         }
     }
 }
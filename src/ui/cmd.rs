@@ -2,16 +2,33 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect}, style::{Color, Style, Stylize}, text::{Line, Span, Text}, widgets::{Block, Borders, Paragraph}, Frame
 };
 
-use crate::{data_models::*, theme::hex_to_color};
+use unicode_width::UnicodeWidthStr;
+
+use crate::{buffer::display_width, data_models::*, theme::hex_to_color};
 
 pub fn render_cmd(frame: &mut Frame<'_>, area: Rect, ctx: &RenderContext){
     let cmd_bg = hex_to_color(ctx.theme.command.background.clone());
 
     let cmd_fg = hex_to_color(ctx.theme.command.foreground.clone());
-    let cmd_text = vec![String::from(": "), ctx.input_buffer.clone()].join("");
+    // A Ctrl+R search shows its query and the history entry it found
+    let (cmd_text, curs_x) = match ctx.history_search {
+        Some(search) => {
+            let prefix = format!("(history)`{}", search.query);
+            let found = search
+                .found
+                .and_then(|i| ctx.history.entries(search.kind).get(i))
+                .map(|s| s.as_str())
+                .unwrap_or("");
+            (format!("{}': {}", prefix, found), prefix.width() as u16)
+        }
+        None => (
+            vec![String::from(": "), ctx.input_buffer.clone()].join(""),
+            display_width(ctx.input_buffer, *ctx.curs_x) as u16 + 2,
+        ),
+    };
     let cmd = Paragraph::new(Text::from(cmd_text).style(cmd_fg)).block(Block::default().borders(Borders::ALL).style(cmd_bg));
     frame.render_widget(cmd, area);
     if let Windows::Command= ctx.focus{
-        frame.set_cursor_position((area.x + 3 + *ctx.curs_x as u16, area.y+1));
+        frame.set_cursor_position((area.x + 1 + curs_x, area.y+1));
     }
 }
\ No newline at end of file
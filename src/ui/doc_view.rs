@@ -1,130 +1,165 @@
-use std::fs;
-
-use color_eyre::owo_colors::{colors::xterm::MatrixPink, OwoColorize};
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Rect}, style::{Color, Style, Stylize}, text::{Line, Span, Text}, widgets::{Block, Borders, Paragraph}, Frame
+    layout::{Constraint, Direction, Layout, Rect}, style::Style, text::{Line, Span, Text}, widgets::{Block, Borders, Paragraph}, Frame
 };
 
-use crate::{data_models::*, theme::hex_to_color};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{buffer::{cell_width, row_of, row_width, view_width, wrap_rows}, config::{Config, LineNumbers}, data_models::*, syntax, theme::hex_to_color};
+
+// Columns taken by the line numbers, they grow with the digits of the last line.
+pub fn gutter_width(doc: &Document, config: &Config) -> u16 {
+    match config.line_numbers {
+        LineNumbers::Off => 0,
+        LineNumbers::On | LineNumbers::Relative => doc.content.len_lines().to_string().len().max(2) as u16 + 2,
+    }
+}
 
-pub fn render_doc_view(frame: &mut Frame<'_>, area: Rect, ctx: &RenderContext) {
-    let selected_doc = &ctx.documents[*ctx.active];
+// Columns left for text in an editor area `width` wide, inside the borders.
+pub fn text_width(width: u16, doc: &Document, config: &Config) -> u16 {
+    width.saturating_sub(gutter_width(doc, config) + 2).max(1)
+}
+
+// Whether the grapheme at (line, col) is selected, the ends can come in either order.
+fn in_selection(selection: Option<((usize, usize), (usize, usize))>, line: usize, col: usize) -> bool {
+    let Some((a, b)) = selection else {
+        return false;
+    };
+    let (start, stop) = if a <= b { (a, b) } else { (b, a) };
+    (line, col) >= start && (line, col) < stop
+}
+
+// Draws `doc` as seen from `view`, only the focused pane shows the cursor.
+pub fn render_doc_view(frame: &mut Frame<'_>, area: Rect, ctx: &RenderContext, selected_doc: &Document, view: &View, focused: bool) {
     let highl = hex_to_color(ctx.theme.editor.highlights.clone());
     let fg_color = hex_to_color(ctx.theme.editor.foreground.clone());
     let bg_color = hex_to_color(ctx.theme.editor.background.clone());
-    //fs::write("log.txt", format!("{:?} {:?} {:?}", highl, fg_color, bg_color)).unwrap();
-    let mut doc_view = selected_doc
-        .content
-        .iter()
-        .enumerate()
-        .map(|(index, line)| {
-            Line::from({
-                // Collect all highlights for the current line and sort them by start index.
-                let mut highlights: Vec<(usize, usize, usize)> = selected_doc
-                    .state
-                    .highlights
-                    .iter()
-                    .filter(|(line_index, _, _)| *line_index == index)
-                    .cloned()
-                    .collect();
-                highlights.sort_by_key(|&(_, start, _)| start);
-
-                let mut spans = Vec::new();
-            
-
-                for (i, ch) in line.char_indices() {
-                    let style = if highlights.iter().any(|&(_, start, end)| i >= start && i < end) {
-                        Style::default().fg(highl)
-                    } else {
-                        Style::default().fg(fg_color)
-                    };
-                    spans.push(Span::styled(ch.to_string(), style));
-                }
-                spans
-            })
-        })
-        .collect::<Vec<Line>>();
-        
-        let offst = selected_doc.state.scroll_offset;
-        let selection= selected_doc.state.selection;
-        match selection {
-            
-            Some(val) => {
-                let (start_y, start_x, stop_y, stop_x) = {
-                    let (y1, x1) = val.0;
-                    let (y2, x2) = val.1;
-                    if y1 <= y2 {
-                        
-                        (y1, x1, y2, x2)
-                    } else {
-                        (y2, x2, y1, x1)
-                    }
+    let tab_width = selected_doc.editorconfig.tab_width(ctx.config);
+
+    let gutter = gutter_width(selected_doc, ctx.config);
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(gutter), Constraint::Min(3)])
+        .split(area);
+
+    // Rows below the top border, wrapped lines take more than one
+    let rows = area.height.saturating_sub(1) as usize;
+    let columns = text_width(area.width, selected_doc, ctx.config) as usize;
+    let wrap_width = if ctx.config.wrap { columns } else { 0 };
+    // Unwrapped lines are scrolled sideways with the cursor
+    let h_scroll = if ctx.config.wrap { 0 } else { view.h_scroll };
+    let cursor_line = view.scroll_offset + view.curs_y;
+    let selection = view.selection;
+
+    let mut doc_view: Vec<Line> = Vec::new();
+    let mut numbers: Vec<Line> = Vec::new();
+    // Cells each row of the view takes, to mark the ones cut off at the edges
+    let mut row_cells: Vec<usize> = Vec::new();
+    // Row and cell of the view the cursor is drawn at
+    let mut cursor_at = None;
+    // Only the visible part of the buffer is turned into lines
+    let mut index = view.scroll_offset;
+    while doc_view.len() < rows && index < selected_doc.content.len_lines() {
+        let line = selected_doc.content.line(index);
+        // Collect all highlights for the current line and sort them by start index.
+        let mut highlights: Vec<(usize, usize, usize)> = selected_doc
+            .state
+            .highlights
+            .iter()
+            .filter(|(line_index, _, _)| *line_index == index)
+            .cloned()
+            .collect();
+        highlights.sort_by_key(|&(_, start, _)| start);
+        // Syntax tokens come as byte ranges of the line
+        let tokens = match &selected_doc.syntax {
+            Some(syntax) => syntax::tokenize(syntax.language, &line, syntax.state_at(index)).0,
+            None => Vec::new(),
+        };
+
+        let graphemes: Vec<(&str, Style)> = line
+            .grapheme_indices(true)
+            .enumerate()
+            .map(|(i, (byte, grapheme))| {
+                let token = tokens.iter().find(|&&(start, end, _)| byte >= start && byte < end);
+                let style = if highlights.iter().any(|&(_, start, end)| i >= start && i < end) {
+                    Style::default().fg(highl)
+                } else if let Some(&(_, _, kind)) = token {
+                    Style::default().fg(hex_to_color(ctx.theme.syntax.color(kind).clone()))
+                } else {
+                    Style::default().fg(fg_color)
                 };
+                let style = if in_selection(selection, index, i) { style.bg(highl) } else { style };
+                (grapheme, style)
+            })
+            .collect();
 
-                for (y, line )in doc_view.iter_mut().enumerate() {
-                    for (x,span )in line.iter_mut().enumerate() {
-
-                        if y == start_y && y == stop_y {
-                            // Single line selection.
-                            
-                            if x >= start_x.min(stop_x) && x < stop_x.max(start_x)  {
-                                *span = Span::styled(span.content.clone(), span.style.bg(highl));
-                            }
-                        } else if y == start_y {
-                            // First line of a multi-line selection.
-                            if x >= start_x {
-                                *span = Span::styled(span.content.clone(), span.style.bg(highl));
-                            }
-                        } else if y > start_y && y < stop_y {
-                            // Entire middle line is selected.
-                            *span = Span::styled(span.content.clone(), span.style.bg(highl));
-                        } else if y == stop_y {
-                            // Last line of a multi-line selection.
-                            if x < stop_x {
-                                *span = Span::styled(span.content.clone(), span.style.bg(highl));
-                            }
-                        }
-                        
-                    
-                            
-                           
-                            
-                        
-                        
-                       
-                            
-                        
-                    }
-                }
+        let starts = if wrap_width > 0 { wrap_rows(&line, wrap_width, tab_width) } else { vec![0] };
+        // The top line can start part way down, see `keep_cursor_in_view`
+        let skip = if wrap_width > 0 && index == view.scroll_offset { view.scroll_row } else { 0 };
+        if index == cursor_line {
+            let row = row_of(&starts, view.curs_x);
+            cursor_at = Some(((doc_view.len() + row).saturating_sub(skip), row_width(&line, starts[row], view.curs_x, tab_width)));
+        }
+        for (row, &start) in starts.iter().enumerate().skip(skip) {
+            let end = starts.get(row + 1).copied().unwrap_or(graphemes.len());
+            let mut spans = Vec::new();
+            let mut cells = 0;
+            for &(grapheme, style) in &graphemes[start..end] {
+                // Tabs are drawn as spaces up to the next tab stop
+                let width = cell_width(grapheme, cells, tab_width);
+                let text = if grapheme == "\t" { " ".repeat(width) } else { grapheme.to_string() };
+                cells += width;
+                spans.push(Span::styled(text, style));
             }
-            None => {}
+            doc_view.push(Line::from(spans));
+            row_cells.push(cells);
+
+            // Only the first row of a line gets its number, centered
+            let label = match ctx.config.line_numbers {
+                _ if row > 0 => String::new(),
+                LineNumbers::Relative if index != cursor_line => index.abs_diff(cursor_line).to_string(),
+                _ => index.to_string(),
+            };
+            numbers.push(Line::from(label).centered());
         }
-        
-    let doc_view_slice = &doc_view[selected_doc.state.scroll_offset..];
-    let doc_view_paragraph = Paragraph::new(Text::from_iter(doc_view_slice.iter().cloned())).style(bg_color)
-        .block(Block::default().borders(Borders::TOP | Borders::LEFT | Borders::RIGHT));
+        index += 1;
+    }
 
-    let chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([Constraint::Length(4), Constraint::Min(3)])
-        .split(area);
+    // With more than one pane the focused one has its border highlighted
+    let border = if focused && ctx.panes.len() > 1 { Style::default().fg(highl) } else { Style::default() };
+    let doc_view_paragraph = Paragraph::new(Text::from_iter(doc_view)).style(bg_color)
+        .scroll((0, h_scroll as u16))
+        .block(Block::default().borders(Borders::TOP | Borders::LEFT | Borders::RIGHT).border_style(border));
 
-    let lines: Vec<Line> = (selected_doc.state.scroll_offset..selected_doc.content.len())
-        .map(|num| {
-            // For each line, draw a line number centered.
-            Line::from(num.to_string()).centered()
-        })
-        .collect();
-    let line_numbers = Text::from(lines);
-    let line_numbers_widget =
-        Paragraph::new(line_numbers).style(fg_color).block(Block::default().borders(Borders::LEFT | Borders::TOP).style(bg_color));
-    frame.render_widget(line_numbers_widget, chunks[0]);
+    if gutter > 0 {
+        let line_numbers_widget =
+            Paragraph::new(Text::from(numbers)).style(fg_color).block(Block::default().borders(Borders::LEFT | Borders::TOP).border_style(border).style(bg_color));
+        frame.render_widget(line_numbers_widget, chunks[0]);
+    }
     frame.render_widget(doc_view_paragraph, chunks[1]);
 
-    let curs_x = chunks[1].x + 1 + selected_doc.state.curs_x as u16;
-    let curs_y = chunks[1].y + 1 + selected_doc.state.curs_y as u16;
+    // `<` and `>` on the edge cells of rows with text scrolled out of sight
+    let text_x = chunks[1].x + 1;
+    let columns = columns.min(chunks[1].width.saturating_sub(2) as usize);
+    for (row, &cells) in row_cells.iter().enumerate() {
+        let y = chunks[1].y + 1 + row as u16;
+        let buf = frame.buffer_mut();
+        if h_scroll > 0 && cells > 0 {
+            buf[(text_x, y)].set_char('<').set_fg(highl);
+        }
+        if columns > 0 && cells > h_scroll + columns {
+            buf[(text_x + columns as u16 - 1, y)].set_char('>').set_fg(highl);
+        }
+    }
+
+    // Wide characters take more than one cell, so place the cursor by display width
+    let (row, cell) = cursor_at.unwrap_or_else(|| {
+        let curs_line = selected_doc.content.line(cursor_line);
+        (view.curs_y, view_width(&curs_line, view.curs_x, tab_width))
+    });
+    let curs_x = chunks[1].x + 1 + cell.saturating_sub(h_scroll) as u16;
+    let curs_y = chunks[1].y + 1 + row as u16;
 
-    if let Windows::Editor = ctx.focus {
+    if focused && matches!(ctx.focus, Windows::Editor) {
         frame.set_cursor_position((curs_x, curs_y));
     }
-}
\ No newline at end of file
+}
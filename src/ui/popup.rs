@@ -1,5 +1,6 @@
-use ratatui::{layout::{Rect, Alignment}, style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Borders, Paragraph}, Frame};
-use crate::{data_models::PopupTypes::{ErrorPopup, SaveOnClosePopup, ThemeSelectPopup, InfoPopup}, theme::hex_to_color};
+use ratatui::{layout::{Constraint, Direction, Layout, Rect, Alignment}, style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Borders, Clear, Paragraph}, Frame};
+use unicode_width::UnicodeWidthStr;
+use crate::{data_models::PopupTypes::{ErrorPopup, SaveOnClosePopup, ThemeSelectPopup, InfoPopup, RecoverSwapPopup, ExternalChangePopup, UndoTreePopup, ReplaceConfirmPopup, CompletionPopup, FileFinderPopup, CommandPalettePopup, OutlinePopup}, actions, finder, theme::hex_to_color};
 use crate::{RenderContext};
 
 
@@ -44,7 +45,7 @@ pub fn render_popup(frame: &mut Frame<'_>,  ctx: &RenderContext){
                         .alignment(Alignment::Center)
                         .style(Style::default().fg(fg))
                 },
-                InfoPopup => {
+                InfoPopup | RecoverSwapPopup | ExternalChangePopup | ReplaceConfirmPopup => {
                     let fg = hex_to_color(ctx.theme.popup.fg.to_owned());
                     let bg = hex_to_color(ctx.theme.popup.bg.to_owned());
                     let mut lines: Vec<Line> = Vec::new();
@@ -61,8 +62,8 @@ pub fn render_popup(frame: &mut Frame<'_>,  ctx: &RenderContext){
                     // bottom padding line, indented
                     lines.push(Line::raw(indent.clone()));
 
-                    // Adjust popup_height based on the number of lines generated
-                    popup_height = lines.len() as u16;
+                    // Adjust popup_height based on the number of lines generated, plus the borders
+                    popup_height = lines.len() as u16 + 2;
                     // Ensure a minimum height if necessary
                     if popup_height < 3 { 
                         popup_height = 3;
@@ -75,6 +76,40 @@ pub fn render_popup(frame: &mut Frame<'_>,  ctx: &RenderContext){
                         .style(Style::default().fg(fg))
                 },
                 
+                // History browser, newest state first, completion candidates and
+                // the outline, scrolled to keep the selection visible
+                UndoTreePopup | CompletionPopup | OutlinePopup => {
+                    let fg = hex_to_color(ctx.theme.popup.fg.to_owned());
+                    let bg = hex_to_color(ctx.theme.popup.bg.to_owned());
+                    popup_width = area.width * 3 / 4;
+                    let (selected, title) = match p.kind {
+                        CompletionPopup => (*ctx.selected_completion, " Completions  [Tab] insert  [Esc] close "),
+                        OutlinePopup => (*ctx.selected_outline, " Outline  [Enter] go to  [Esc] close "),
+                        _ => (*ctx.selected_undo, " History  [Enter] go to  [Esc] close "),
+                    };
+                    let visible = (area.height as usize).saturating_sub(2).max(1);
+                    let first = selected.saturating_sub(visible - 1);
+                    let lines: Vec<Line> = p.msg
+                        .split('\n')
+                        .enumerate()
+                        .skip(first)
+                        .map(|(i, entry)| {
+                            if i == selected {
+                                Line::raw(entry.to_string()).reversed()
+                            } else {
+                                Line::raw(entry.to_string())
+                            }
+                        })
+                        .collect();
+                    popup_height = height as u16 + 2;
+                    Paragraph::new(Text::from(lines))
+                        .block(Block::default().borders(Borders::ALL).title(title).style(bg))
+                        .style(Style::default().fg(fg))
+                }
+
+                FileFinderPopup => return render_file_finder(frame, ctx),
+                CommandPalettePopup => return render_command_palette(frame, ctx),
+
                 // Set styles for the theme selection popup
                 ThemeSelectPopup => {
                 let fg = hex_to_color(ctx.theme.popup.fg.to_owned());
@@ -111,6 +146,8 @@ pub fn render_popup(frame: &mut Frame<'_>,  ctx: &RenderContext){
                         .style(Style::default().fg(fg))
                 }
             };
+            // Never grow past the terminal, the overflow is clipped instead
+            popup_height = popup_height.min(area.height);
             let  popup_x = area.x + (area.width - popup_width) / 2;
             let  popup_y = area.y + (area.height - popup_height) / 2;
             let popup_area = Rect::new(popup_x, popup_y, popup_width, popup_height);
@@ -122,4 +159,113 @@ pub fn render_popup(frame: &mut Frame<'_>,  ctx: &RenderContext){
     
 
 
-}
\ No newline at end of file
+}
+
+// Query line on top, matching files on the left and a preview of the
+// highlighted one on the right.
+fn render_file_finder(frame: &mut Frame<'_>, ctx: &RenderContext) {
+    let Some(finder) = ctx.finder else {
+        return;
+    };
+    let fg = hex_to_color(ctx.theme.popup.fg.to_owned());
+    let bg = hex_to_color(ctx.theme.popup.bg.to_owned());
+    let area = frame.area();
+    let width = area.width * 9 / 10;
+    let height = area.height * 4 / 5;
+    let popup_area = Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height);
+    frame.render_widget(Clear, popup_area);
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(1)])
+        .split(popup_area);
+    let panes = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(45), Constraint::Percentage(55)])
+        .split(rows[1]);
+
+    let title = format!(" Files {}/{}  [Enter] open  [Esc] close ", finder.matches.len(), finder.files.len());
+    let prompt = format!("> {}", finder.query);
+    let input = Paragraph::new(prompt.clone())
+        .block(Block::default().borders(Borders::ALL).title(title).style(bg))
+        .style(Style::default().fg(fg));
+    frame.render_widget(input, rows[0]);
+    frame.set_cursor_position((rows[0].x + 1 + prompt.width() as u16, rows[0].y + 1));
+
+    let visible = (panes[0].height as usize).saturating_sub(2).max(1);
+    let first = finder.selected.saturating_sub(visible - 1);
+    let list: Vec<Line> = finder
+        .matches
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(visible)
+        .map(|(i, file)| {
+            let line = Line::raw(finder.files[*file].clone());
+            if i == finder.selected { line.reversed() } else { line }
+        })
+        .collect();
+    let list = Paragraph::new(Text::from(list))
+        .block(Block::default().borders(Borders::ALL).style(bg))
+        .style(Style::default().fg(fg));
+    frame.render_widget(list, panes[0]);
+
+    let selected = finder.matches.get(finder.selected).map(|i| finder.files[*i].as_str()).unwrap_or("");
+    let preview: Vec<Line> = match finder.selected_path() {
+        Some(path) => finder::preview_lines(&path, panes[1].height as usize).into_iter().map(Line::raw).collect(),
+        None => Vec::new(),
+    };
+    let preview = Paragraph::new(Text::from(preview))
+        .block(Block::default().borders(Borders::ALL).title(format!(" {} ", selected)).style(bg))
+        .style(Style::default().fg(fg));
+    frame.render_widget(preview, panes[1]);
+}
+
+// Query line on top of the matching actions, each with its description and key.
+fn render_command_palette(frame: &mut Frame<'_>, ctx: &RenderContext) {
+    let Some(palette) = ctx.palette else {
+        return;
+    };
+    let fg = hex_to_color(ctx.theme.popup.fg.to_owned());
+    let bg = hex_to_color(ctx.theme.popup.bg.to_owned());
+    let area = frame.area();
+    let width = area.width * 7 / 10;
+    let height = area.height * 3 / 5;
+    let popup_area = Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height);
+    frame.render_widget(Clear, popup_area);
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(1)])
+        .split(popup_area);
+
+    let title = format!(" Actions {}/{}  [Enter] run  [Esc] close ", palette.matches.len(), actions::ACTIONS.len());
+    let prompt = format!("> {}", palette.query);
+    let input = Paragraph::new(prompt.clone())
+        .block(Block::default().borders(Borders::ALL).title(title).style(bg))
+        .style(Style::default().fg(fg));
+    frame.render_widget(input, rows[0]);
+    frame.set_cursor_position((rows[0].x + 1 + prompt.width() as u16, rows[0].y + 1));
+
+    let name_width = actions::ACTIONS.iter().map(|a| a.name.len()).max().unwrap_or(0);
+    let visible = (rows[1].height as usize).saturating_sub(2).max(1);
+    let first = palette.selected.saturating_sub(visible - 1);
+    let list: Vec<Line> = palette
+        .matches
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(visible)
+        .map(|(i, index)| {
+            let action = &actions::ACTIONS[*index];
+            let keys = ctx.keymap.keys_for(action.name);
+            let key = if keys.is_empty() { String::new() } else { format!("  [{}]", keys.join(", ")) };
+            let line = Line::raw(format!("{:<3$}  {}{}", action.name, action.description, key, name_width));
+            if i == palette.selected { line.reversed() } else { line }
+        })
+        .collect();
+    let list = Paragraph::new(Text::from(list))
+        .block(Block::default().borders(Borders::ALL).style(bg))
+        .style(Style::default().fg(fg));
+    frame.render_widget(list, rows[1]);
+}
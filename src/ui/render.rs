@@ -89,12 +89,13 @@ pub fn render_ui(f: &mut Frame<'_>, ctx: &RenderContext) -> LayoutSnapshot {
             tab_area: chunks[1],
             editor_area: chunks[2],
             command_area: chunks[3],
+            pane_areas: Vec::new(),
         };
     }
 
     render_status_bar(f, chunks[0], ctx);
     render_tab_bar(f, chunks[1], ctx);
-    render_doc_view(f, chunks[2], ctx);
+    let pane_areas = render_panes(f, chunks[2], ctx);
     render_cmd(f, chunks[3], ctx);
     render_popup(f, ctx);
 
@@ -103,5 +104,26 @@ pub fn render_ui(f: &mut Frame<'_>, ctx: &RenderContext) -> LayoutSnapshot {
         tab_area: chunks[1],
         editor_area: chunks[2],
         command_area: chunks[3],
+        pane_areas,
     }
 }
+
+// Draws every pane in its part of the editor area and returns those parts.
+fn render_panes(f: &mut Frame<'_>, area: Rect, ctx: &RenderContext) -> Vec<Rect> {
+    let mut areas = Vec::new();
+    ctx.pane_layout.areas(area, &mut areas);
+    let mut pane_areas = vec![Rect::default(); ctx.panes.len()];
+    for (index, pane_area) in areas {
+        let focused = index == *ctx.focused_pane;
+        // The focused pane's view is the one its document holds
+        let (doc, view) = match ctx.panes.get(index) {
+            Some(pane) if !focused && pane.doc < ctx.documents.len() => (&ctx.documents[pane.doc], pane.view),
+            _ => (&ctx.documents[*ctx.active], ctx.documents[*ctx.active].state.view()),
+        };
+        render_doc_view(f, pane_area, ctx, doc, &view, focused);
+        if let Some(slot) = pane_areas.get_mut(index) {
+            *slot = pane_area;
+        }
+    }
+    pane_areas
+}
@@ -2,7 +2,7 @@ use std::path::{self, PathBuf};
 
 use ratatui::{Frame, layout::Rect, text::Line, widgets::Paragraph};
 
-use crate::{data_models::*, theme::hex_to_color};
+use crate::{data_models::*, keymap, theme::hex_to_color};
 
 pub fn render_status_bar(frame: &mut Frame<'_>, area: Rect, ctx: &RenderContext) {
     let fg = hex_to_color(ctx.theme.status.foreground.clone());
@@ -16,14 +16,34 @@ pub fn render_status_bar(frame: &mut Frame<'_>, area: Rect, ctx: &RenderContext)
     } else {
         "Unsaved"
     };
+    let state = &ctx.documents[*ctx.active].state;
+    let match_info = if state.find_active && !state.highlights.is_empty() {
+        format!(" | match {} of {}", state.current_match + 1, state.highlights.len())
+    } else {
+        String::new()
+    };
+    // Start of a key sequence waiting for the next key
+    let pending = if ctx.pending_keys.is_empty() {
+        String::new()
+    } else {
+        format!(" | keys: {} ...", keymap::sequence_label(ctx.pending_keys))
+    };
+    let language = match &ctx.documents[*ctx.active].syntax {
+        Some(syntax) => format!(" | {}", syntax.language.name),
+        None => String::new(),
+    };
     let status_text = format!(
-        "Tpad | Line: {} Col: {} |{}| Size: {} | tabs: {} | path: {}",
+        "Tpad | Line: {} Col: {} |{}| Size: {} | {}{} | tabs: {} | path: {}{}{}",
         cursor_info.1,
         cursor_info.0,
         permissions,
         ctx.documents[*ctx.active].size,
+        ctx.documents[*ctx.active].format_label(),
+        language,
         ctx.documents.len(),
-        strip_path(ctx.default_dir.clone())
+        strip_path(ctx.default_dir.clone()),
+        match_info,
+        pending
     );
     let status_bar = Paragraph::new(Line::from(status_text).left_aligned().style(fg));
     frame.render_widget(status_bar, area);